@@ -1,39 +1,721 @@
-use std::path::Path;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
-use anyhow::Error;
+use anyhow::{anyhow, bail, Error};
+use clap::ValueEnum;
 use itertools::{repeat_n, Itertools};
+use num::Complex;
 
-use crate::png;
+use crate::{
+    formula::Formula,
+    png,
+    projection::{Projection, Z_PLANE},
+    sampler::{EscapeTest, Precision, RenderMode, SamplingMethod, DEFAULT_ESCAPE_RADIUS},
+};
 
-pub fn gather_data<P, N>(bundle_files: Vec<P>) -> Result<(u32, u32, u32, Vec<N>), Error>
+/// Name of the zip entry holding the `key = value` render settings.
+pub const MANIFEST_NAME: &str = "manifest.txt";
+
+/// Version byte written at the start of `data.bin`.
+///
+/// 1: u32 counts with an optional manifest, only the size and iterations
+/// are known for certain. 2: the manifest is required and holds the full
+/// render settings, except for what was lost merging version 1 bundles.
+/// 3: as 2 with u64 counts.
+pub const FORMAT_VERSION: u8 = 3;
+
+/// Description of the counts stored in a bundle.
+///
+/// `width`, `height` and `max_iters` come from the `data.bin` header, the
+/// rest from the manifest. Bundles written before the manifest existed get
+/// the defaults and leave the optional fields empty. The counts hold
+/// `width * height` values per channel.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BundleMeta {
+    pub width: u32,
+    pub height: u32,
+    pub max_iters: u32,
+    pub channel_iters: Vec<u32>,
+    pub min_iters: u32,
+    pub mode: RenderMode,
+    pub sampling: SamplingMethod,
+    pub formula: String,
+    pub escape_test: EscapeTest,
+    pub escape_radius: f64,
+    pub precision: Precision,
+    /// Sample window and the zoom window binned into the frame
+    pub lower_left: Option<Complex<f64>>,
+    pub upper_right: Option<Complex<f64>>,
+    pub zoom_lower_left: Option<Complex<f64>>,
+    pub zoom_upper_right: Option<Complex<f64>>,
+    /// Fixed c of a Julia set render, `None` when the samples are c
+    pub julia: Option<Complex<f64>>,
+    /// Rows of the matrix projecting `(z.re, z.im, c.re, c.im)` onto the
+    /// frame
+    pub projection: Projection,
+    /// Number of trials summed into the counts
+    pub samples: Option<u64>,
+    /// Trials per backend update
+    pub batch_trials: Option<u32>,
+    /// Seed and run index the counts were sampled with, if known
+    pub seed: Option<u64>,
+    pub run: Option<u64>,
+    /// `(seed, run)` pairs of the runs summed into a merged bundle, which
+    /// has no single seed and run
+    pub runs: Vec<(u64, u64)>,
+}
+
+fn enum_name<T: ValueEnum>(v: &T) -> String {
+    v.to_possible_value().unwrap().get_name().to_string()
+}
+
+fn parse_enum<T: ValueEnum>(key: &str, value: &str) -> Result<T, Error> {
+    T::from_str(value, true).map_err(|_| anyhow!("invalid value for {key}: {value}"))
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, Error> {
+    value
+        .parse()
+        .map_err(|_| anyhow!("invalid value for {key}: {value}"))
+}
+
+fn parse_complex(key: &str, value: &str) -> Result<Complex<f64>, Error> {
+    let Some((re, im)) = value.split_once(',') else {
+        bail!("invalid value for {key}: {value}");
+    };
+    Ok(Complex::new(
+        parse_value(key, re.trim())?,
+        parse_value(key, im.trim())?,
+    ))
+}
+
+fn parse_projection(key: &str, value: &str) -> Result<Projection, Error> {
+    let m = value
+        .split(',')
+        .map(|v| parse_value(key, v.trim()))
+        .collect::<Result<Vec<f64>, _>>()?;
+    let Ok(m) = <[f64; 8]>::try_from(m) else {
+        bail!("invalid value for {key}: {value}, expected 8 numbers");
+    };
+    Ok([[m[0], m[1], m[2], m[3]], [m[4], m[5], m[6], m[7]]])
+}
+
+fn parse_runs(key: &str, value: &str) -> Result<Vec<(u64, u64)>, Error> {
+    value
+        .split(',')
+        .map(|pair| {
+            let Some((seed, run)) = pair.split_once(':') else {
+                bail!("invalid value for {key}: {value}, expected seed:run pairs");
+            };
+            Ok((
+                parse_value(key, seed.trim())?,
+                parse_value(key, run.trim())?,
+            ))
+        })
+        .collect()
+}
+
+impl BundleMeta {
+    pub fn new(width: u32, height: u32, max_iters: u32) -> Self {
+        Self {
+            width,
+            height,
+            max_iters,
+            channel_iters: vec![max_iters],
+            min_iters: 0,
+            mode: RenderMode::default(),
+            sampling: SamplingMethod::default(),
+            formula: Formula::default().to_string(),
+            escape_test: EscapeTest::default(),
+            escape_radius: DEFAULT_ESCAPE_RADIUS,
+            precision: Precision::default(),
+            lower_left: None,
+            upper_right: None,
+            zoom_lower_left: None,
+            zoom_upper_right: None,
+            julia: None,
+            projection: Z_PLANE,
+            samples: None,
+            batch_trials: None,
+            seed: None,
+            run: None,
+            runs: vec![],
+        }
+    }
+
+    pub fn num_channels(&self) -> u32 {
+        self.channel_iters.len() as u32
+    }
+
+    /// `(seed, run)` pairs of every run summed into the counts that are
+    /// known.
+    pub fn sampled_runs(&self) -> Vec<(u64, u64)> {
+        match (self.seed, self.run) {
+            (Some(seed), Some(run)) => vec![(seed, run)],
+            _ => self.runs.clone(),
+        }
+    }
+
+    pub fn to_manifest(&self) -> String {
+        let mut s = String::new();
+        s.push_str(&format!("mode = {}\n", enum_name(&self.mode)));
+        s.push_str(&format!(
+            "channel_iterations = {}\n",
+            self.channel_iters.iter().join(",")
+        ));
+        s.push_str(&format!("min_iterations = {}\n", self.min_iters));
+        s.push_str(&format!("sampling = {}\n", enum_name(&self.sampling)));
+        s.push_str(&format!("formula = {}\n", self.formula));
+        s.push_str(&format!("escape_test = {}\n", enum_name(&self.escape_test)));
+        s.push_str(&format!("escape_radius = {}\n", self.escape_radius));
+        s.push_str(&format!("precision = {}\n", enum_name(&self.precision)));
+        let windows = [
+            ("lower_left", self.lower_left),
+            ("upper_right", self.upper_right),
+            ("zoom_lower_left", self.zoom_lower_left),
+            ("zoom_upper_right", self.zoom_upper_right),
+            ("julia", self.julia),
+        ];
+        for (key, value) in windows {
+            if let Some(c) = value {
+                s.push_str(&format!("{key} = {},{}\n", c.re, c.im));
+            }
+        }
+        s.push_str(&format!(
+            "projection = {}\n",
+            self.projection.iter().flatten().join(",")
+        ));
+        if let Some(samples) = self.samples {
+            s.push_str(&format!("samples = {samples}\n"));
+        }
+        if let Some(batch_trials) = self.batch_trials {
+            s.push_str(&format!("batch_trials = {batch_trials}\n"));
+        }
+        if let Some(seed) = self.seed {
+            s.push_str(&format!("seed = {seed}\n"));
+        }
+        if let Some(run) = self.run {
+            s.push_str(&format!("run = {run}\n"));
+        }
+        if !self.runs.is_empty() {
+            s.push_str(&format!(
+                "runs = {}\n",
+                self.runs
+                    .iter()
+                    .map(|(seed, run)| format!("{seed}:{run}"))
+                    .join(",")
+            ));
+        }
+        s
+    }
+
+    /// The render settings as a JSON object with the manifest's keys plus
+    /// the image size, for tools reading exported counts.
+    pub fn to_json(&self) -> String {
+        let complex = |c: Option<Complex<f64>>| match c {
+            Some(c) => format!("[{}, {}]", c.re, c.im),
+            None => "null".to_string(),
+        };
+        let optional = |v: Option<u64>| v.map_or("null".to_string(), |v| v.to_string());
+        let fields = [
+            ("width", self.width.to_string()),
+            ("height", self.height.to_string()),
+            ("max_iterations", self.max_iters.to_string()),
+            ("mode", format!("\"{}\"", enum_name(&self.mode))),
+            (
+                "channel_iterations",
+                format!("[{}]", self.channel_iters.iter().join(", ")),
+            ),
+            ("min_iterations", self.min_iters.to_string()),
+            ("sampling", format!("\"{}\"", enum_name(&self.sampling))),
+            ("formula", format!("\"{}\"", self.formula)),
+            (
+                "escape_test",
+                format!("\"{}\"", enum_name(&self.escape_test)),
+            ),
+            ("escape_radius", self.escape_radius.to_string()),
+            ("precision", format!("\"{}\"", enum_name(&self.precision))),
+            ("lower_left", complex(self.lower_left)),
+            ("upper_right", complex(self.upper_right)),
+            ("zoom_lower_left", complex(self.zoom_lower_left)),
+            ("zoom_upper_right", complex(self.zoom_upper_right)),
+            ("julia", complex(self.julia)),
+            (
+                "projection",
+                format!(
+                    "[{}]",
+                    self.projection
+                        .iter()
+                        .map(|row| format!("[{}]", row.iter().join(", ")))
+                        .join(", ")
+                ),
+            ),
+            ("samples", optional(self.samples)),
+            ("batch_trials", optional(self.batch_trials.map(u64::from))),
+            ("seed", optional(self.seed)),
+            ("run", optional(self.run)),
+            (
+                "runs",
+                format!(
+                    "[{}]",
+                    self.runs
+                        .iter()
+                        .map(|(seed, run)| format!("[{seed}, {run}]"))
+                        .join(", ")
+                ),
+            ),
+        ];
+        let body = fields
+            .iter()
+            .map(|(key, value)| format!("  \"{key}\": {value}"))
+            .join(",\n");
+        format!("{{\n{body}\n}}\n")
+    }
+
+    pub fn apply_manifest(&mut self, manifest: &str) -> Result<(), Error> {
+        let mut entries = BTreeMap::new();
+        for line in manifest.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((key, value)) = line.split_once('=') else {
+                bail!("malformed manifest line: {line}");
+            };
+            entries.insert(key.trim(), value.trim());
+        }
+
+        for (key, value) in entries {
+            match key {
+                "mode" => self.mode = parse_enum(key, value)?,
+                "sampling" => self.sampling = parse_enum(key, value)?,
+                "precision" => self.precision = parse_enum(key, value)?,
+                "min_iterations" => self.min_iters = parse_value(key, value)?,
+                "channel_iterations" => {
+                    self.channel_iters = value
+                        .split(',')
+                        .map(|v| parse_value(key, v.trim()))
+                        .collect::<Result<_, _>>()?
+                }
+                "formula" => self.formula = value.to_string(),
+                "escape_test" => self.escape_test = parse_enum(key, value)?,
+                "escape_radius" => self.escape_radius = parse_value(key, value)?,
+                "lower_left" => self.lower_left = Some(parse_complex(key, value)?),
+                "upper_right" => self.upper_right = Some(parse_complex(key, value)?),
+                "zoom_lower_left" => self.zoom_lower_left = Some(parse_complex(key, value)?),
+                "zoom_upper_right" => self.zoom_upper_right = Some(parse_complex(key, value)?),
+                "julia" => self.julia = Some(parse_complex(key, value)?),
+                "projection" => self.projection = parse_projection(key, value)?,
+                "samples" => self.samples = Some(parse_value(key, value)?),
+                "batch_trials" => self.batch_trials = Some(parse_value(key, value)?),
+                "seed" => self.seed = Some(parse_value(key, value)?),
+                "run" => self.run = Some(parse_value(key, value)?),
+                "runs" => self.runs = parse_runs(key, value)?,
+                _ => log::warn!("ignoring unknown manifest entry {key}"),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Settings two bundles must share to be summed, with a readable value
+/// or `None` when the bundle does not record it.
+fn checked_fields(m: &BundleMeta) -> [(&'static str, Option<String>); 15] {
+    let complex = |c: Option<Complex<f64>>| c.map(|c| format!("{},{}", c.re, c.im));
+    [
+        ("width", Some(m.width.to_string())),
+        ("height", Some(m.height.to_string())),
+        ("channels", Some(m.num_channels().to_string())),
+        ("max iterations", Some(m.max_iters.to_string())),
+        ("channel iterations", Some(m.channel_iters.iter().join(","))),
+        ("min iterations", Some(m.min_iters.to_string())),
+        ("mode", Some(enum_name(&m.mode))),
+        ("sampling", Some(enum_name(&m.sampling))),
+        ("formula", Some(m.formula.clone())),
+        (
+            "escape test",
+            Some(format!("{} {}", enum_name(&m.escape_test), m.escape_radius)),
+        ),
+        (
+            "julia c",
+            Some(complex(m.julia).unwrap_or("off".to_string())),
+        ),
+        ("projection", Some(m.projection.iter().flatten().join(","))),
+        ("lower left", complex(m.lower_left)),
+        ("upper right", complex(m.upper_right)),
+        ("zoom window", {
+            complex(m.zoom_lower_left)
+                .zip(complex(m.zoom_upper_right))
+                .map(|(ll, ur)| format!("{ll} to {ur}"))
+        }),
+    ]
+}
+
+/// The counts of bundles with a different shape can't be summed at all.
+const SHAPE_FIELDS: [&str; 3] = ["width", "height", "channels"];
+
+/// Settings that decide where an orbit lands in the frame, which bundles
+/// composed into one image must share.
+const VIEW_FIELDS: [&str; 6] = [
+    "width",
+    "height",
+    "lower left",
+    "upper right",
+    "zoom window",
+    "projection",
+];
+
+/// Compares the `fields` of `m`, read from `name`, against the first
+/// bundle. Mismatches are errors unless `force` is set, in which case they
+/// are only logged. Settings one of the bundles does not record are not
+/// compared.
+fn check_compatible(
+    name: &str,
+    first_name: &str,
+    first: &BundleMeta,
+    m: &BundleMeta,
+    force: bool,
+    fields: &[&str],
+) -> Result<(), Error> {
+    let mut mismatches = vec![];
+    let mut shape_mismatch = false;
+    for ((field, expected), (_, value)) in checked_fields(first).into_iter().zip(checked_fields(m))
+    {
+        if !fields.contains(&field) {
+            continue;
+        }
+        if let (Some(expected), Some(value)) = (expected, value) {
+            if expected != value {
+                mismatches.push(format!("{field} is {value}, expected {expected}"));
+                shape_mismatch |= SHAPE_FIELDS.contains(&field);
+            }
+        }
+    }
+
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    let message = format!(
+        "{name} does not match {first_name}: {}",
+        mismatches.join(", ")
+    );
+    if shape_mismatch {
+        bail!(message);
+    }
+    if !force {
+        bail!("{message} (use --force to combine them anyway)");
+    }
+    log::warn!("{message}");
+    Ok(())
+}
+
+/// Checks that bundles composed into one image, e.g. as its red and green
+/// channels, cover the same view. Unlike `gather_data` their iterations
+/// and sampling may differ.
+pub fn check_same_view(
+    name: &str,
+    first_name: &str,
+    first: &BundleMeta,
+    m: &BundleMeta,
+    force: bool,
+) -> Result<(), Error> {
+    check_compatible(name, first_name, first, m, force, &VIEW_FIELDS)
+}
+
+/// Sums the counts of all bundles. The result is described by the first
+/// bundle's metadata, with the sample counts added up.
+pub fn gather_data<P, N>(bundle_files: Vec<P>, force: bool) -> Result<(BundleMeta, Vec<N>), Error>
 where
     P: AsRef<Path>,
     N: num::Unsigned + num::Zero + Clone + num::PrimInt,
 {
+    let Some(first_path) = bundle_files.first() else {
+        bail!("no bundle files given");
+    };
+
     let mut data = None;
-    let mut width = 0;
-    let mut height = 0;
-    let mut iterations = 0;
+    let mut meta: Option<BundleMeta> = None;
+    let mut samples = Some(0u64);
+    let mut batch_trials = None;
+    let mut seen_runs: HashMap<(u64, u64), PathBuf> = HashMap::new();
     for bpath in bundle_files.iter() {
-        let (w, h, i, partial_data) = png::read_bundle_data(&bpath)?;
-        width = w;
-        height = h;
-        iterations = i;
-        if data == None {
-            data = Some(repeat_n(N::zero(), partial_data.len()).collect_vec());
+        let (m, partial_data) = png::read_bundle_data(bpath)?;
+        if let Some(ref first) = meta {
+            let all_fields = checked_fields(first).map(|(field, _)| field);
+            check_compatible(
+                &bpath.as_ref().display().to_string(),
+                &first_path.as_ref().display().to_string(),
+                first,
+                &m,
+                force,
+                &all_fields,
+            )?;
+        }
+        for (seed, run) in m.sampled_runs() {
+            if let Some(other) = seen_runs.insert((seed, run), bpath.as_ref().to_path_buf()) {
+                log::warn!(
+                    "{} and {} both hold seed {seed} run {run}, its samples are counted twice",
+                    other.display(),
+                    bpath.as_ref().display()
+                );
+            }
+        }
+        samples = samples.zip(m.samples).map(|(a, b)| a + b);
+        if meta.is_none() {
+            batch_trials = m.batch_trials;
+            meta = Some(m);
+        } else if batch_trials != m.batch_trials {
+            batch_trials = None;
+        }
+
+        let d = data.get_or_insert_with(|| repeat_n(N::zero(), partial_data.len()).collect_vec());
+        for (a, b) in d.iter_mut().zip(partial_data.iter()) {
+            *a = N::from(*b)
+                .and_then(|b| a.checked_add(&b))
+                .ok_or_else(|| anyhow!("counts overflowed adding {}", bpath.as_ref().display()))?;
+        }
+    }
+
+    let mut meta = meta.unwrap();
+    meta.samples = samples;
+    meta.batch_trials = batch_trials;
+    // a sum of several runs is not reproducible from a single seed, but
+    // the runs are kept to catch merging them again
+    if bundle_files.len() > 1 {
+        meta.seed = None;
+        meta.run = None;
+        meta.runs = seen_runs.into_keys().sorted().collect();
+    }
+
+    Ok((meta, data.unwrap()))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::projection::{self, PlaneRotation};
+
+    /// Path in the temp directory unique to this process and `name`.
+    pub(crate) fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("buddhabrot-{}-{name}", std::process::id()))
+    }
+
+    /// Settings with every field away from its default.
+    pub(crate) fn full_meta() -> BundleMeta {
+        BundleMeta {
+            channel_iters: vec![500, 50],
+            min_iters: 3,
+            mode: RenderMode::Anti,
+            sampling: SamplingMethod::Metropolis,
+            formula: "multibrot:3".to_string(),
+            escape_test: EscapeTest::Manhattan,
+            escape_radius: 4.5,
+            precision: Precision::Double,
+            lower_left: Some(Complex::new(-2.0, -1.25)),
+            upper_right: Some(Complex::new(1.0, 1.25)),
+            zoom_lower_left: Some(Complex::new(-0.75, 0.1)),
+            zoom_upper_right: Some(Complex::new(-0.5, 0.3)),
+            julia: Some(Complex::new(-0.8, 0.156)),
+            projection: projection::rotate(
+                projection::Z_PLANE,
+                &["zr-cr=30".parse::<PlaneRotation>().unwrap()],
+            ),
+            samples: Some(123456789),
+            batch_trials: Some(6400),
+            seed: Some(42),
+            run: Some(7),
+            ..BundleMeta::new(4, 3, 500)
         }
+    }
 
-        let Some(ref mut d) = data else {
-            unreachable!();
+    #[test]
+    fn manifest_round_trip() {
+        let merged = BundleMeta {
+            seed: None,
+            run: None,
+            runs: vec![(42, 1), (42, 2), (43, 1)],
+            ..full_meta()
         };
+        for meta in [BundleMeta::new(4, 3, 500), full_meta(), merged] {
+            let mut read = BundleMeta::new(meta.width, meta.height, meta.max_iters);
+            read.apply_manifest(&meta.to_manifest()).unwrap();
+            assert_eq!(read, meta);
+        }
+    }
 
-        assert_eq!(d.len(), partial_data.len());
+    #[test]
+    fn manifest_skips_comments_and_unknown_keys() {
+        let mut meta = BundleMeta::new(4, 3, 500);
+        meta.apply_manifest("# written by hand\n\nmode = anti\ncolour = blue\n")
+            .unwrap();
+        assert_eq!(meta.mode, RenderMode::Anti);
+    }
 
-        d.iter_mut().zip(partial_data.iter()).for_each(|(a, b)| {
-            let tmp = N::from(*b).unwrap();
-            *a = *a + tmp;
+    #[test]
+    fn malformed_manifest_is_rejected() {
+        for manifest in [
+            "mode",
+            "mode = sideways",
+            "min_iterations = -1",
+            "lower_left = 1",
+            "projection = 1,0,0,0",
+            "runs = 7",
+        ] {
+            let mut meta = BundleMeta::new(4, 3, 500);
+            assert!(meta.apply_manifest(manifest).is_err(), "{manifest}");
+        }
+    }
+
+    /// Writes bundles of `meta` with their counts to temp files, calls `f`
+    /// with the paths and removes the files again.
+    pub(crate) fn with_bundles<R>(
+        name: &str,
+        bundles: &[(BundleMeta, Vec<u64>)],
+        f: impl FnOnce(&[PathBuf]) -> R,
+    ) -> R {
+        let paths = bundles
+            .iter()
+            .enumerate()
+            .map(|(n, (meta, data))| {
+                let path = temp_path(&format!("{name}-{n}.zip"));
+                crate::fractal::dump_to_file(meta, data, &path, false).unwrap();
+                path
+            })
+            .collect::<Vec<_>>();
+        let result = f(&paths);
+        for path in &paths {
+            std::fs::remove_file(path).unwrap();
+        }
+        result
+    }
+
+    fn bundle(meta: BundleMeta) -> (BundleMeta, Vec<u64>) {
+        let len = (meta.width * meta.height * meta.num_channels()) as usize;
+        (meta, vec![1; len])
+    }
+
+    #[test]
+    fn mismatched_settings_name_file_and_field() {
+        let other = BundleMeta {
+            min_iters: 4,
+            ..full_meta()
+        };
+        let bundles = [bundle(full_meta()), bundle(other)];
+        let err = with_bundles("mismatch", &bundles, |paths| {
+            let err = gather_data::<_, u64>(paths.to_vec(), false).unwrap_err();
+            assert!(err.to_string().contains(&paths[1].display().to_string()));
+            err
         });
+        assert!(err.to_string().contains("min iterations is 4, expected 3"));
+        assert!(err.to_string().contains("--force"));
+    }
+
+    #[test]
+    fn force_merges_mismatched_settings() {
+        let other = BundleMeta {
+            mode: RenderMode::Buddhabrot,
+            formula: "tricorn".to_string(),
+            ..full_meta()
+        };
+        let bundles = [bundle(full_meta()), bundle(other)];
+        let (meta, data) = with_bundles("force", &bundles, |paths| {
+            gather_data::<_, u64>(paths.to_vec(), true)
+        })
+        .unwrap();
+        assert_eq!(meta.mode, RenderMode::Anti);
+        assert!(data.iter().all(|c| *c == 2));
     }
 
-    Ok((width, height, iterations, data.unwrap()))
+    #[test]
+    fn shape_mismatch_fails_even_with_force() {
+        let first = full_meta();
+        let others = [
+            BundleMeta {
+                width: 5,
+                ..full_meta()
+            },
+            BundleMeta {
+                height: 2,
+                ..full_meta()
+            },
+            BundleMeta {
+                channel_iters: vec![500],
+                ..full_meta()
+            },
+        ];
+        for (n, other) in others.into_iter().enumerate() {
+            let bundles = [bundle(first.clone()), bundle(other)];
+            let err = with_bundles(&format!("shape{n}"), &bundles, |paths| {
+                gather_data::<_, u64>(paths.to_vec(), true)
+            })
+            .unwrap_err();
+            assert!(!err.to_string().contains("--force"), "{err}");
+        }
+    }
+
+    #[test]
+    fn same_view_ignores_iterations() {
+        let first = full_meta();
+        let other = BundleMeta {
+            channel_iters: vec![50],
+            max_iters: 50,
+            sampling: SamplingMethod::Uniform,
+            ..full_meta()
+        };
+        check_same_view("green", "red", &first, &other, false).unwrap();
+
+        let zoomed = BundleMeta {
+            zoom_upper_right: Some(Complex::new(-0.4, 0.3)),
+            ..full_meta()
+        };
+        let err = check_same_view("green", "red", &first, &zoomed, false).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("green does not match red: zoom window"));
+        check_same_view("green", "red", &first, &zoomed, true).unwrap();
+    }
+
+    #[test]
+    fn merged_bundle_keeps_its_runs() {
+        let bundles = [7, 3].map(|run| {
+            bundle(BundleMeta {
+                run: Some(run),
+                ..full_meta()
+            })
+        });
+        let (meta, _) = with_bundles("runs", &bundles, |paths| {
+            gather_data::<_, u64>(paths.to_vec(), false)
+        })
+        .unwrap();
+        assert_eq!((meta.seed, meta.run), (None, None));
+        assert_eq!(meta.runs, vec![(42, 3), (42, 7)]);
+        assert_eq!(meta.sampled_runs(), meta.runs);
+
+        // a single bundle keeps its seed and run
+        let (meta, _) = with_bundles("run", &bundles[..1], |paths| {
+            gather_data::<_, u64>(paths.to_vec(), false)
+        })
+        .unwrap();
+        assert_eq!((meta.seed, meta.run), (Some(42), Some(7)));
+        assert_eq!(meta.sampled_runs(), vec![(42, 7)]);
+    }
+
+    #[test]
+    fn merge_fails_instead_of_wrapping() {
+        let meta = BundleMeta::new(2, 1, 500);
+        let bundles = [
+            (meta.clone(), vec![u64::MAX - 1, 5]),
+            (meta.clone(), vec![1, 5]),
+            (meta, vec![1, 5]),
+        ];
+        let err = with_bundles("overflow", &bundles, |paths| {
+            let sum = gather_data::<_, u64>(paths[..2].to_vec(), false).unwrap().1;
+            assert_eq!(sum, vec![u64::MAX, 10]);
+            gather_data::<_, u64>(paths.to_vec(), false).unwrap_err()
+        });
+        assert!(err.to_string().contains("overflowed"), "{err}");
+    }
 }
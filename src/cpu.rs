@@ -0,0 +1,617 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{ensure, Error};
+use num::{Complex, Float};
+
+use crate::{
+    formula::Formula,
+    sampler::{
+        add_counts, EscapeTest, Precision, RenderMode, RenderParams, SampleBatch, SampleStats,
+        Sampler, SamplingMethod, MAX_CHANNELS,
+    },
+};
+
+// see the constants of the same name in shader.wgsl
+const MH_WEIGHT: f32 = 1024.0;
+const MH_SMALL_STEP: f32 = 0.8;
+
+/// Multithreaded CPU implementation of the kernel in `shader.wgsl`.
+///
+/// The arithmetic is done in the same order as the shader, in `f32` or
+/// `f64` depending on the precision. GPU `pow`, `atan2` and fused
+/// multiply-adds round differently, so the two backends don't match bit
+/// for bit, but their bundles are statistically equivalent and can be
+/// merged.
+pub struct CPUHandle {
+    width: u32,
+    height: u32,
+    threads: usize,
+    chains: Vec<Chain>,
+}
+
+/// Metropolis-Hastings chain of one trial, same as `Chain` in the shader.
+/// The sample is kept in `f64`, which holds `f32` samples exactly.
+#[derive(Copy, Clone, Default)]
+struct Chain {
+    p1: f64,
+    p2: f64,
+    contrib: u32,
+    mask: u32,
+}
+
+#[derive(Copy, Clone)]
+struct CPUVars<T> {
+    width: u32,
+    height: u32,
+    max_iterations: u32,
+    mode: RenderMode,
+    channel_iterations: [u32; MAX_CHANNELS],
+    num_channels: u32,
+    min_iterations: u32,
+    interior_check: bool,
+    formula: Formula,
+    escape_test: EscapeTest,
+    /// Escape radius, squared for the circle test
+    escape_limit: f32,
+    ll: Complex<T>,
+    ur: Complex<T>,
+    zoom_ll: Complex<T>,
+    zoom_ur: Complex<T>,
+    julia: Option<Complex<T>>,
+    /// Rows of the projection, rounded to `f32` like in `GPUVars`
+    projection: [[T; 4]; 2],
+    seed: u64,
+}
+
+/// Starting point and parameter of an orbit, same as `Orbit` in the shader.
+#[derive(Copy, Clone)]
+struct Orbit<T> {
+    z0: Complex<T>,
+    c: Complex<T>,
+}
+
+/// Same as `pcg4d` in the shader.
+fn pcg4d(v: [u32; 4]) -> [u32; 4] {
+    fn mix(v: &mut [u32; 4]) {
+        v[0] = v[0].wrapping_add(v[1].wrapping_mul(v[3]));
+        v[1] = v[1].wrapping_add(v[2].wrapping_mul(v[0]));
+        v[2] = v[2].wrapping_add(v[0].wrapping_mul(v[1]));
+        v[3] = v[3].wrapping_add(v[1].wrapping_mul(v[2]));
+    }
+
+    let mut v = v.map(|x| x.wrapping_mul(1664525).wrapping_add(1013904223));
+    mix(&mut v);
+    v = v.map(|x| x ^ (x >> 16));
+    mix(&mut v);
+    v
+}
+
+/// Same as `r` in the shader preludes.
+fn r<T: Float>(x: f32) -> T {
+    T::from(x).unwrap()
+}
+
+/// Same as `r2` in the shader preludes, single precision only keeps `hi`.
+fn r2<T: Float>(hi: f32, lo: f32) -> T {
+    if T::epsilon() < r(f32::EPSILON) {
+        r::<T>(hi) + r(lo)
+    } else {
+        r(hi)
+    }
+}
+
+/// Same as `r_uniform` in the shader.
+fn r_uniform<T: Float>(hi: f32, lo: f32) -> T {
+    r2(hi, lo / 16777216.0)
+}
+
+fn to_f32<T: Float>(x: T) -> f32 {
+    x.to_f32().unwrap()
+}
+
+impl<T: Float> CPUVars<T> {
+    /// Same as `random4` in the shader.
+    fn random4(&self, t: u32, k: u32) -> [f32; 4] {
+        pcg4d([t, k, self.seed as u32, (self.seed >> 32) as u32])
+            .map(|x| (x >> 8) as f32 / 16777216.0)
+    }
+
+    fn project(row: [T; 4], z: Complex<T>, c: Complex<T>) -> T {
+        (row[0] * z.re + row[1] * z.im) + (row[2] * c.re + row[3] * c.im)
+    }
+
+    fn world_to_screen(&self, z: Complex<T>, c: Complex<T>) -> (f32, f32) {
+        let w = self.width as f32;
+        let h = self.height as f32;
+        let px = Self::project(self.projection[0], z, c);
+        let py = Self::project(self.projection[1], z, c);
+        let x = to_f32(px - self.zoom_ll.re) / to_f32(self.zoom_ur.re - self.zoom_ll.re) * w;
+        let y = to_f32(py - self.zoom_ll.im) / to_f32(self.zoom_ur.im - self.zoom_ll.im) * h;
+        (x, y)
+    }
+
+    fn channel_counts(&self, escape_iters: u32, ch: u32) -> bool {
+        let limit = self.channel_iterations[ch as usize];
+        if self.mode == RenderMode::Anti {
+            return escape_iters >= limit;
+        }
+        escape_iters < limit
+    }
+
+    /// Same as `sample_orbit` in the shader.
+    fn sample_orbit(&self, p1: T, p2: T) -> Orbit<T> {
+        let re = p1 * (self.ur.re - self.ll.re) + self.ll.re;
+        let im = p2 * (self.ur.im - self.ll.im) + self.ll.im;
+        match self.julia {
+            Some(c) => Orbit {
+                z0: Complex::new(re, im),
+                c,
+            },
+            None => Orbit {
+                z0: Complex::new(T::zero(), T::zero()),
+                c: Complex::new(re, im),
+            },
+        }
+    }
+
+    /// One step of the orbit, same as the `orbit_step` generated in
+    /// `shader.rs` for the formula.
+    fn orbit_step(&self, z: Complex<T>, c: Complex<T>) -> Complex<T> {
+        let (re, im) = match self.formula {
+            Formula::Mandelbrot => (
+                z.re * z.re - z.im * z.im + c.re,
+                (z.re + z.re) * z.im + c.im,
+            ),
+            Formula::Multibrot(d) => {
+                let mut w = z;
+                for _ in 1..d {
+                    w = Complex::new(w.re * z.re - w.im * z.im, w.re * z.im + w.im * z.re);
+                }
+                (w.re + c.re, w.im + c.im)
+            }
+            Formula::MultibrotReal(d) => {
+                let power = d as f32;
+                let x = to_f32(z.re);
+                let y = to_f32(z.im);
+                if x == 0.0 && y == 0.0 {
+                    return c;
+                }
+                let m = (x * x + y * y).powf(power * 0.5);
+                let a = y.atan2(x) * power;
+                (r::<T>(m * a.cos()) + c.re, r::<T>(m * a.sin()) + c.im)
+            }
+            Formula::BurningShip => (
+                z.re * z.re - z.im * z.im + c.re,
+                (z.re + z.re).abs() * z.im.abs() + c.im,
+            ),
+            Formula::Tricorn => (
+                z.re * z.re - z.im * z.im + c.re,
+                c.im - (z.re + z.re) * z.im,
+            ),
+            Formula::Celtic => (
+                (z.re * z.re - z.im * z.im).abs() + c.re,
+                (z.re + z.re) * z.im + c.im,
+            ),
+        };
+        Complex::new(re, im)
+    }
+
+    /// Same as the `escaped` generated in `shader.rs`.
+    fn escaped(&self, z: Complex<T>) -> bool {
+        match self.escape_test {
+            EscapeTest::Circle => to_f32(z.re * z.re + z.im * z.im) > self.escape_limit,
+            EscapeTest::Real => to_f32(z.re).abs() > self.escape_limit,
+            EscapeTest::Imag => to_f32(z.im).abs() > self.escape_limit,
+            EscapeTest::Manhattan => to_f32(z.re).abs() + to_f32(z.im).abs() > self.escape_limit,
+            EscapeTest::Square => to_f32(z.re).abs().max(to_f32(z.im).abs()) > self.escape_limit,
+        }
+    }
+
+    /// Counts the sample in `stats` and returns true when it lies in the main
+    /// cardioid or the period-2 bulb, which never escape.
+    fn interior_region(&self, c: Complex<T>, stats: &mut SampleStats) -> bool {
+        if !self.interior_check {
+            return false;
+        }
+        let x = c.re - r(0.25);
+        let y2 = c.im * c.im;
+        let q = x * x + y2;
+        if q * (q + x) <= r::<T>(0.25) * y2 {
+            stats.cardioid += 1;
+            return true;
+        }
+        let x1 = c.re + T::one();
+        if x1 * x1 + y2 <= r(0.0625) {
+            stats.bulb += 1;
+            return true;
+        }
+        false
+    }
+
+    fn escape_iterations(&self, o: Orbit<T>) -> u32 {
+        // check for escape, max_iterations is the highest channel limit
+        let mut iters = 0u32;
+        let mut z = o.z0;
+        loop {
+            iters += 1;
+            if iters >= self.max_iterations {
+                break;
+            }
+
+            z = self.orbit_step(z, o.c);
+
+            if self.escaped(z) {
+                break;
+            }
+        }
+        iters
+    }
+
+    /// Runs the escape test and returns a bit per channel the orbit counts in.
+    fn orbit_mask(&self, o: Orbit<T>, stats: &mut SampleStats) -> u32 {
+        let mut iters = self.max_iterations;
+        if !self.interior_region(o.c, stats) {
+            iters = self.escape_iterations(o);
+        } else if self.mode != RenderMode::Anti {
+            // interior orbits are exactly what the anti-Buddhabrot counts
+            return 0;
+        }
+
+        // short orbits are dropped
+        if iters < self.min_iterations {
+            return 0;
+        }
+
+        // work out which channels this orbit contributes to
+        (0..self.num_channels)
+            .filter(|ch| self.channel_counts(iters, *ch))
+            .fold(0u32, |mask, ch| mask | (1 << ch))
+    }
+
+    /// Walks the orbit again adding `weight` to every pixel it lands on and
+    /// returns the number of hits. A weight of 0 only counts the hits.
+    fn orbit_points(
+        &self,
+        o: Orbit<T>,
+        channel_mask: u32,
+        weight: u32,
+        counts: &[AtomicU64],
+    ) -> u32 {
+        // bounded orbits stop at the channel limit
+        let channel_size = self.width as usize * self.height as usize;
+        let mut hits = 0;
+        let mut iters = 0u32;
+        let mut z = o.z0;
+        loop {
+            iters += 1;
+            if iters >= self.max_iterations {
+                break;
+            }
+
+            z = self.orbit_step(z, o.c);
+
+            if self.escaped(z) {
+                break;
+            }
+
+            let (x, y) = self.world_to_screen(z, o.c);
+            if x >= 0.0 && (x as u32) < self.width && y >= 0.0 && (y as u32) < self.height {
+                let idx = y as usize * self.width as usize + x as usize;
+                for ch in 0..self.num_channels {
+                    if channel_mask & (1 << ch) != 0 && iters < self.channel_iterations[ch as usize]
+                    {
+                        hits += 1;
+                        if weight > 0 {
+                            counts[ch as usize * channel_size + idx]
+                                .fetch_add(weight as u64, Ordering::Relaxed);
+                        }
+                    }
+                }
+            }
+        }
+        hits
+    }
+
+    fn buddhabrot_iterations(&self, p1: T, p2: T, counts: &[AtomicU64], stats: &mut SampleStats) {
+        let o = self.sample_orbit(p1, p2);
+        let channel_mask = self.orbit_mask(o, stats);
+        if channel_mask == 0 {
+            return;
+        }
+        self.orbit_points(o, channel_mask, 1, counts);
+    }
+
+    /// One Metropolis-Hastings step, see `metropolis_iterations` in the shader.
+    fn metropolis_iterations(
+        &self,
+        t: u32,
+        chain: &mut Chain,
+        counts: &[AtomicU64],
+        stats: &mut SampleStats,
+    ) {
+        let mut p1 = T::from(chain.p1).unwrap();
+        let mut p2 = T::from(chain.p2).unwrap();
+        let r0 = self.random4(t, 0);
+        let r1 = self.random4(t, 1);
+        let lo = self.random4(t, 2);
+        let u1 = r_uniform(r0[0], lo[0]);
+        let u2 = r_uniform(r0[1], lo[1]);
+
+        if chain.contrib == 0 {
+            // no starting point yet, try the uniform sample
+            let o = self.sample_orbit(u1, u2);
+            let channel_mask = self.orbit_mask(o, stats);
+            let mut hits = 0;
+            if channel_mask != 0 {
+                hits = self.orbit_points(o, channel_mask, 0, counts);
+            }
+            if hits == 0 {
+                return;
+            }
+            p1 = u1;
+            p2 = u2;
+            chain.contrib = hits;
+            chain.mask = channel_mask;
+        } else {
+            let mut q1 = u1;
+            let mut q2 = u2;
+            if r0[2] < MH_SMALL_STEP {
+                // symmetric step scaled to the zoom window over several octaves
+                let scale = (-8.0 * r0[3]).exp2();
+                let sigma_re =
+                    to_f32(self.zoom_ur.re - self.zoom_ll.re) / to_f32(self.ur.re - self.ll.re);
+                let sigma_im =
+                    to_f32(self.zoom_ur.im - self.zoom_ll.im) / to_f32(self.ur.im - self.ll.im);
+                q1 = p1 + r((r1[0] - 0.5) * 2.0 * scale * sigma_re);
+                q2 = p2 + r((r1[1] - 0.5) * 2.0 * scale * sigma_im);
+            }
+
+            let mut hits = 0;
+            let mut channel_mask = 0;
+            let unit = T::zero()..T::one();
+            if unit.contains(&q1) && unit.contains(&q2) {
+                let o = self.sample_orbit(q1, q2);
+                channel_mask = self.orbit_mask(o, stats);
+                if channel_mask != 0 {
+                    hits = self.orbit_points(o, channel_mask, 0, counts);
+                }
+            }
+
+            if hits > 0 && r1[2] * (chain.contrib as f32) < hits as f32 {
+                p1 = q1;
+                p2 = q2;
+                chain.contrib = hits;
+                chain.mask = channel_mask;
+            }
+        }
+
+        // spread MH_WEIGHT over the orbit, rounding stochastically
+        let w = MH_WEIGHT / chain.contrib as f32;
+        let weight = w.floor() as u32 + u32::from(r1[3] < w - w.floor());
+        chain.p1 = p1.to_f64().unwrap();
+        chain.p2 = p2.to_f64().unwrap();
+        if weight > 0 {
+            let o = self.sample_orbit(p1, p2);
+            self.orbit_points(o, chain.mask, weight, counts);
+        }
+    }
+}
+
+impl CPUHandle {
+    pub fn new(width: u32, height: u32, threads: usize) -> Self {
+        CPUHandle {
+            width,
+            height,
+            threads: threads.max(1),
+            chains: vec![],
+        }
+    }
+
+    fn run<T: Float + Send + Sync>(
+        &mut self,
+        params: &RenderParams,
+        vars: CPUVars<T>,
+        batch: &SampleBatch,
+        frame: &mut [u64],
+    ) -> Result<SampleStats, Error> {
+        let trials = batch.trials as usize;
+        let chunk_size = trials.div_ceil(self.threads).max(1);
+        let frame_size = params.frame_len();
+        let sampling = params.sampling;
+        self.chains.resize(trials, Chain::default());
+
+        // the threads add into one shared frame rather than a frame each, so
+        // memory doesn't grow with the thread count
+        let counts = (0..frame_size)
+            .map(|_| AtomicU64::new(0))
+            .collect::<Vec<_>>();
+        let counts = &counts;
+
+        let partials = std::thread::scope(|s| {
+            let handles = self
+                .chains
+                .chunks_mut(chunk_size)
+                .enumerate()
+                .map(|(n, chain_chunk)| {
+                    s.spawn(move || {
+                        let mut stats = SampleStats::default();
+                        for (k, chain) in chain_chunk.iter_mut().enumerate() {
+                            let t = (n * chunk_size + k) as u32;
+                            match sampling {
+                                SamplingMethod::Uniform => {
+                                    let rnd = vars.random4(t, 0);
+                                    vars.buddhabrot_iterations(
+                                        r_uniform(rnd[0], rnd[2]),
+                                        r_uniform(rnd[1], rnd[3]),
+                                        counts,
+                                        &mut stats,
+                                    )
+                                }
+                                SamplingMethod::Metropolis => {
+                                    vars.metropolis_iterations(t, chain, counts, &mut stats)
+                                }
+                            }
+                        }
+                        stats
+                    })
+                })
+                .collect::<Vec<_>>();
+
+            handles
+                .into_iter()
+                .map(|h| h.join().expect("cpu worker panicked"))
+                .collect::<Vec<_>>()
+        });
+
+        let mut stats = SampleStats::default();
+        for partial_stats in partials {
+            stats += partial_stats;
+        }
+        add_counts(frame, counts.iter().map(|c| c.load(Ordering::Relaxed)))?;
+
+        Ok(stats)
+    }
+}
+
+fn vars<T: Float>(params: &RenderParams, batch: &SampleBatch) -> CPUVars<T> {
+    let complex = |c: Complex<f64>| Complex::new(T::from(c.re).unwrap(), T::from(c.im).unwrap());
+    CPUVars {
+        width: params.width,
+        height: params.height,
+        max_iterations: params.max_iters,
+        mode: params.mode,
+        channel_iterations: {
+            let mut v = [0; MAX_CHANNELS];
+            v[..params.channel_iters.len()].copy_from_slice(&params.channel_iters);
+            v
+        },
+        num_channels: params.num_channels(),
+        min_iterations: params.min_iters,
+        interior_check: params.interior_check,
+        formula: params.formula,
+        escape_test: params.escape_test,
+        escape_limit: params.escape_limit(),
+        ll: complex(params.lower_left),
+        ur: complex(params.upper_right),
+        zoom_ll: complex(params.zoom_lower_left),
+        zoom_ur: complex(params.zoom_upper_right),
+        julia: params.julia.map(complex),
+        projection: params.projection.map(|row| row.map(|m| r(m as f32))),
+        seed: batch.seed,
+    }
+}
+
+impl Sampler for CPUHandle {
+    fn reset(&mut self) {
+        self.chains.clear();
+    }
+
+    fn accumulate(
+        &mut self,
+        params: &RenderParams,
+        batch: &SampleBatch,
+        frame: &mut [u64],
+    ) -> Result<SampleStats, Error> {
+        ensure!(
+            params.width == self.width && params.height == self.height,
+            "render size {}x{} does not match the CPU backend ({}x{})",
+            params.width,
+            params.height,
+            self.width,
+            self.height
+        );
+        ensure!(
+            frame.len() == params.frame_len(),
+            "frame holds {} counts, expected {}",
+            frame.len(),
+            params.frame_len()
+        );
+
+        match params.precision {
+            Precision::Single => self.run(params, vars::<f32>(params, batch), batch, frame),
+            Precision::Double => self.run(params, vars::<f64>(params, batch), batch, frame),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::projection::Z_PLANE;
+    use crate::sampler::DEFAULT_ESCAPE_RADIUS;
+
+    fn params(sampling: SamplingMethod, precision: Precision) -> RenderParams {
+        RenderParams {
+            width: 64,
+            height: 48,
+            max_iters: 200,
+            channel_iters: vec![200, 20],
+            min_iters: 0,
+            mode: RenderMode::Buddhabrot,
+            sampling,
+            formula: Formula::Mandelbrot,
+            escape_test: EscapeTest::Circle,
+            escape_radius: DEFAULT_ESCAPE_RADIUS,
+            interior_check: true,
+            precision,
+            lower_left: Complex::new(-2.25, -1.5),
+            upper_right: Complex::new(1.0, 1.5),
+            zoom_lower_left: Complex::new(-2.25, -1.5),
+            zoom_upper_right: Complex::new(1.0, 1.5),
+            julia: None,
+            projection: Z_PLANE,
+        }
+    }
+
+    /// Counts of two batches of a fresh backend with `threads` threads.
+    fn render(params: &RenderParams, threads: usize) -> Vec<u64> {
+        let mut cpu = CPUHandle::new(params.width, params.height, threads);
+        let mut frame = vec![0; params.frame_len()];
+        for seed in [7, 8] {
+            let batch = SampleBatch { seed, trials: 640 };
+            cpu.accumulate(params, &batch, &mut frame).unwrap();
+        }
+        frame
+    }
+
+    #[test]
+    fn fixed_seed_counts_are_deterministic() {
+        for sampling in [SamplingMethod::Uniform, SamplingMethod::Metropolis] {
+            for precision in [Precision::Single, Precision::Double] {
+                let params = params(sampling, precision);
+                let counts = render(&params, 1);
+
+                // every channel got some orbits
+                for channel in counts.chunks(params.frame_len() / 2) {
+                    assert!(channel.iter().any(|c| *c > 0), "{sampling:?} {precision:?}");
+                }
+                assert_eq!(counts, render(&params, 1), "{sampling:?} {precision:?}");
+                assert_eq!(counts, render(&params, 3), "{sampling:?} {precision:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn batch_seed_changes_counts() {
+        let params = params(SamplingMethod::Uniform, Precision::Single);
+        let mut cpu = CPUHandle::new(params.width, params.height, 2);
+        let frames = [7, 8].map(|seed| {
+            let mut frame = vec![0; params.frame_len()];
+            let batch = SampleBatch { seed, trials: 640 };
+            cpu.accumulate(&params, &batch, &mut frame).unwrap();
+            frame
+        });
+        assert_ne!(frames[0], frames[1]);
+    }
+
+    #[test]
+    fn double_precision_samples_use_two_words() {
+        let hi = 0.5 + 3.0 / 16777216.0;
+        let lo = 0.25;
+        assert_eq!(r_uniform::<f32>(hi, lo), hi);
+        assert_eq!(r_uniform::<f64>(hi, lo), hi as f64 + 0.25 / 16777216.0);
+        // the largest sample stays below 1
+        let max = 16777215.0 / 16777216.0;
+        assert!(r_uniform::<f64>(max, max) < 1.0);
+    }
+}
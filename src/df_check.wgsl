@@ -0,0 +1,11 @@
+// Self-check of the double-float prelude: a product and a sum of the input
+// pairs, whose low parts are lost when the device doesn't fuse fma or
+// reassociates the additions.
+@group(0) @binding(0) var<storage, read_write> df_check: array<vec2f, 6>;
+
+@compute
+@workgroup_size(1, 1, 1)
+fn main() {
+    df_check[4] = r_mul(df_check[0], df_check[1]);
+    df_check[5] = r_add(df_check[2], df_check[3]);
+}
@@ -0,0 +1,154 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, bail, ensure, Error};
+
+/// Highest multibrot power, an integer power d takes d - 1 complex
+/// multiplications per step.
+pub const MAX_POWER: u32 = 64;
+
+/// Iteration applied to the orbits, `z = f(z, c)` starting from `z = 0`.
+///
+/// Formulas are written as a name with an optional parameter after a
+/// colon, e.g. `multibrot:3` or `multibrot:2.5`, which is also how they are
+/// recorded in bundles.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Formula {
+    /// z^2 + c
+    #[default]
+    Mandelbrot,
+    /// z^d + c for an integer power 2 < d <= `MAX_POWER`
+    Multibrot(u32),
+    /// z^d + c for a real power 1 < d < `MAX_POWER`, evaluated in polar form in single
+    /// precision
+    MultibrotReal(f64),
+    /// (|re z| + i |im z|)^2 + c
+    BurningShip,
+    /// conj(z)^2 + c, also known as the Mandelbar
+    Tricorn,
+    /// |re(z^2)| + i im(z^2) + c
+    Celtic,
+}
+
+impl Formula {
+    /// Whether the analytic cardioid and bulb tests apply, which is only
+    /// true for the Mandelbrot set.
+    pub fn has_interior_check(&self) -> bool {
+        *self == Formula::Mandelbrot
+    }
+
+    fn multibrot(power: &str) -> Result<Self, Error> {
+        if let Ok(d) = power.parse::<u32>() {
+            ensure!(
+                (2..=MAX_POWER).contains(&d),
+                "multibrot power must be above 1 and at most {MAX_POWER}, got {d}"
+            );
+            return Ok(if d == 2 {
+                Formula::Mandelbrot
+            } else {
+                Formula::Multibrot(d)
+            });
+        }
+        let d: f64 = power
+            .parse()
+            .map_err(|_| anyhow!("invalid multibrot power: {power}"))?;
+        ensure!(
+            d > 1.0 && d <= MAX_POWER as f64,
+            "multibrot power must be above 1 and at most {MAX_POWER}, got {d}"
+        );
+        if d.fract() == 0.0 {
+            return Self::multibrot(&(d as u32).to_string());
+        }
+        Ok(Formula::MultibrotReal(d))
+    }
+}
+
+impl fmt::Display for Formula {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Formula::Mandelbrot => write!(f, "mandelbrot"),
+            Formula::Multibrot(d) => write!(f, "multibrot:{d}"),
+            Formula::MultibrotReal(d) => write!(f, "multibrot:{d}"),
+            Formula::BurningShip => write!(f, "burning-ship"),
+            Formula::Tricorn => write!(f, "tricorn"),
+            Formula::Celtic => write!(f, "celtic"),
+        }
+    }
+}
+
+impl FromStr for Formula {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let (name, param) = match s.split_once(':') {
+            Some((name, param)) => (name, Some(param.trim())),
+            None => (s, None),
+        };
+        let formula = match (name.trim().to_lowercase().as_str(), param) {
+            ("mandelbrot", None) => Formula::Mandelbrot,
+            ("multibrot", Some(d)) => Formula::multibrot(d)?,
+            ("multibrot", None) => bail!("multibrot needs a power, e.g. multibrot:3"),
+            ("burning-ship", None) => Formula::BurningShip,
+            ("tricorn" | "mandelbar", None) => Formula::Tricorn,
+            ("celtic", None) => Formula::Celtic,
+            ("mandelbrot" | "burning-ship" | "tricorn" | "mandelbar" | "celtic", Some(_)) => {
+                bail!("formula {name} takes no parameter")
+            }
+            _ => bail!(
+                "unknown formula {s}, expected mandelbrot, multibrot:<power>, burning-ship, \
+                 tricorn or celtic"
+            ),
+        };
+        Ok(formula)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formulas_round_trip() {
+        for formula in [
+            Formula::Mandelbrot,
+            Formula::Multibrot(3),
+            Formula::Multibrot(MAX_POWER),
+            Formula::MultibrotReal(2.5),
+            Formula::MultibrotReal(1.01),
+            Formula::BurningShip,
+            Formula::Tricorn,
+            Formula::Celtic,
+        ] {
+            assert_eq!(formula.to_string().parse::<Formula>().unwrap(), formula);
+        }
+    }
+
+    #[test]
+    fn formulas_are_normalised() {
+        let parse = |s: &str| s.parse::<Formula>().unwrap();
+        assert_eq!(parse("multibrot:2"), Formula::Mandelbrot);
+        assert_eq!(parse("multibrot:2.0"), Formula::Mandelbrot);
+        assert_eq!(parse("multibrot:4.0"), Formula::Multibrot(4));
+        assert_eq!(parse(" Multibrot : 3 "), Formula::Multibrot(3));
+        assert_eq!(parse("mandelbar"), Formula::Tricorn);
+    }
+
+    #[test]
+    fn invalid_formulas_are_rejected() {
+        for s in [
+            "multibrot",
+            "multibrot:1",
+            "multibrot:0.5",
+            "multibrot:-3",
+            "multibrot:65",
+            "multibrot:64.5",
+            "multibrot:1e300",
+            "multibrot:inf",
+            "multibrot:nan",
+            "multibrot:x",
+            "mandelbrot:2",
+            "julia",
+        ] {
+            assert!(s.parse::<Formula>().is_err(), "{s}");
+        }
+    }
+}
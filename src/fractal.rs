@@ -1,129 +1,231 @@
-use anyhow::Error;
-use std::{fs::File, path::Path, sync::Mutex, time::SystemTime};
+use anyhow::{ensure, Error};
+use std::path::Path;
+
+use crate::{
+    bundle::{BundleMeta, FORMAT_VERSION, MANIFEST_NAME},
+    cpu::CPUHandle,
+    gpu::GPUHandle,
+    naming,
+    projection::Projection,
+    sampler::{RenderParams, SampleBatch, SampleStats, Sampler, MAX_CHANNELS},
+};
+
+/// Selects one of the built-in backends.
+pub enum BackendKind {
+    Gpu,
+    Cpu { threads: usize },
+}
 
-use itertools::{izip, Itertools};
-use num::complex::Complex;
-use rand::{thread_rng, Rng};
+pub struct BuddhabrotGPU {
+    sampler: Box<dyn Sampler>,
+    batch_trials: u32,
+    params: RenderParams,
+    seed: u64,
+    run: u64,
+    updates: u64,
+    pub frame: Vec<u64>,
+    /// Backend counters and the number of trials in the current run
+    pub stats: SampleStats,
+    pub trials: u64,
+}
 
-use crate::gpu::GPUHandle;
+/// Rounds the requested trial count up to what the GPU dispatch expects.
+pub fn batch_trials(gpu_trials: u32) -> u32 {
+    gpu_trials.max(1).next_multiple_of(6400)
+}
 
-pub struct BuddhabrotGPU {
-    gpu: GPUHandle,
-    num_trials_x2: u32,
-    width: u32,
-    height: u32,
-    lower_left: Complex<f32>,
-    upper_right: Complex<f32>,
-    zoom_lower_left: Complex<f32>,
-    zoom_upper_right: Complex<f32>,
-    pub frame: Vec<u32>,
-    max_iters: u32,
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
 }
 
-fn get_rng_block(n: u32) -> Vec<f32> {
-    let mut r = thread_rng();
-    let mut v = vec![];
-    for _ in 0..n {
-        v.push(r.gen());
-    }
-    v
+/// Seed of the `update`th batch of run `run`. Every run of a seed gets its
+/// own sample stream, so runs can be rendered in any order or repeated.
+pub fn batch_seed(seed: u64, run: u64, update: u64) -> u64 {
+    splitmix64(splitmix64(splitmix64(seed) ^ run) ^ update)
 }
 
 impl BuddhabrotGPU {
     pub fn new(
-        width: u32,
-        height: u32,
-        max_iters: u32,
+        params: RenderParams,
         gpu_trials: u32,
-        lower_left: Complex<f32>,
-        upper_right: Complex<f32>,
-        zoom_lower_left: Complex<f32>,
-        zoom_upper_right: Complex<f32>,
-    ) -> Self {
-        let num_trials_x2 = (gpu_trials * 2).next_multiple_of(6400);
-        let gpu = GPUHandle::new(num_trials_x2, width, height);
+        seed: u64,
+        backend: BackendKind,
+    ) -> Result<Self, Error> {
+        ensure!(
+            !params.channel_iters.is_empty() && params.channel_iters.len() <= MAX_CHANNELS,
+            "between 1 and {MAX_CHANNELS} channels are supported, got {}",
+            params.channel_iters.len()
+        );
+        ensure!(
+            params
+                .channel_iters
+                .iter()
+                .all(|i| *i > 0 && *i <= params.max_iters),
+            "channel iteration limits must be between 1 and max_iters ({})",
+            params.max_iters
+        );
+
+        ensure!(
+            params.min_iters < params.max_iters,
+            "min_iters ({}) must be below max_iters ({})",
+            params.min_iters,
+            params.max_iters
+        );
+
+        ensure!(
+            params.escape_radius > 0.0 && params.escape_limit().is_finite(),
+            "escape radius must be positive and fit in an f32 (squared for the circle test), got {}",
+            params.escape_radius
+        );
 
+        ensure!(
+            !params.interior_check
+                || (params.formula.has_interior_check()
+                    && params.julia.is_none()
+                    && params.escape_test.contains_disc(params.escape_radius)),
+            "the interior check only applies to the mandelbrot formula outside Julia mode with an escape radius of at least 2"
+        );
+
+        let batch_trials = batch_trials(gpu_trials);
+        let sampler: Box<dyn Sampler> = match backend {
+            BackendKind::Gpu => Box::new(GPUHandle::new(batch_trials, &params)?),
+            BackendKind::Cpu { threads } => {
+                Box::new(CPUHandle::new(params.width, params.height, threads))
+            }
+        };
+
+        Ok(Self::with_sampler(params, batch_trials, seed, sampler))
+    }
+
+    /// Builds a renderer around any `Sampler`. Each update asks it for a
+    /// batch of `batch_trials` trials seeded from `seed` and the run index.
+    pub fn with_sampler(
+        params: RenderParams,
+        batch_trials: u32,
+        seed: u64,
+        sampler: Box<dyn Sampler>,
+    ) -> Self {
         Self {
-            gpu,
-            num_trials_x2,
-            width,
-            height,
-            lower_left,
-            upper_right,
-            zoom_lower_left,
-            zoom_upper_right,
-            frame: vec![0; (width * height) as usize],
-            max_iters,
+            sampler,
+            batch_trials,
+            frame: vec![0; params.frame_len()],
+            params,
+            seed,
+            run: 0,
+            updates: 0,
+            stats: SampleStats::default(),
+            trials: 0,
         }
     }
 
-    pub fn reset(&mut self) {
+    /// Clears the frame and the sampler state and starts run `run`. The
+    /// counts of a run only depend on the seed and the run index.
+    pub fn start_run(&mut self, run: u64) {
         self.frame.iter_mut().for_each(|x| *x = 0);
+        self.stats = SampleStats::default();
+        self.trials = 0;
+        self.sampler.reset();
+        self.run = run;
+        self.updates = 0;
+    }
+
+    /// Changes the projection of the orbits onto the frame, e.g. to rotate
+    /// it between the runs of an animation. Takes effect with the next run.
+    pub fn set_projection(&mut self, projection: Projection) {
+        self.params.projection = projection;
     }
 
     pub fn dump_stats(&self) {
-        let sum = self.frame.iter().map(|x| *x as u64).sum::<u64>();
-        let max = self.frame.iter().map(|x| *x as u64).max();
+        let sum = self.frame.iter().map(|x| *x as u128).sum::<u128>();
+        let max = self.frame.iter().max();
 
         log::info!("sum: {sum}, minmax: {max:?}");
+        if self.params.interior_check && self.trials > 0 {
+            let percent = |n: u64| 100.0 * n as f64 / self.trials as f64;
+            log::info!(
+                "interior check: cardioid {} ({:.1}%), bulb {} ({:.1}%) of {} trials",
+                self.stats.cardioid,
+                percent(self.stats.cardioid),
+                self.stats.bulb,
+                percent(self.stats.bulb),
+                self.trials
+            );
+        }
     }
 
-    pub fn update(&mut self) {
-        let result = self.gpu.call(
-            self.lower_left,
-            self.upper_right,
-            self.zoom_lower_left,
-            self.zoom_upper_right,
-            self.max_iters,
-            get_rng_block(self.num_trials_x2),
-        );
-        for (n, v) in self.frame.iter_mut().enumerate() {
-            let c = result[n];
-            *v += c;
+    pub fn update(&mut self) -> Result<(), Error> {
+        let batch = SampleBatch {
+            seed: batch_seed(self.seed, self.run, self.updates),
+            trials: self.batch_trials,
+        };
+        self.updates += 1;
+        self.stats += self
+            .sampler
+            .accumulate(&self.params, &batch, &mut self.frame)?;
+        self.trials += batch.trials as u64;
+        Ok(())
+    }
+
+    pub fn metadata(&self) -> BundleMeta {
+        BundleMeta {
+            mode: self.params.mode,
+            channel_iters: self.params.channel_iters.clone(),
+            min_iters: self.params.min_iters,
+            sampling: self.params.sampling,
+            formula: self.params.formula.to_string(),
+            escape_test: self.params.escape_test,
+            escape_radius: self.params.escape_radius,
+            precision: self.params.precision,
+            lower_left: Some(self.params.lower_left),
+            upper_right: Some(self.params.upper_right),
+            zoom_lower_left: Some(self.params.zoom_lower_left),
+            zoom_upper_right: Some(self.params.zoom_upper_right),
+            julia: self.params.julia,
+            projection: self.params.projection,
+            samples: Some(self.trials),
+            batch_trials: Some(self.batch_trials),
+            seed: Some(self.seed),
+            run: Some(self.run),
+            ..BundleMeta::new(self.params.width, self.params.height, self.params.max_iters)
         }
     }
 
-    pub fn dump_to_file(&self, prefix: &str) -> Result<(), Error> {
-        dump_to_file(self.width, self.height, self.max_iters, &self.frame, prefix)
+    pub fn dump_to_file(&self, path: &Path, overwrite: bool) -> Result<(), Error> {
+        dump_to_file(&self.metadata(), &self.frame, path, overwrite)
     }
 }
 
 pub fn dump_to_file(
-    width: u32,
-    height: u32,
-    max_iters: u32,
-    frame: &Vec<u32>,
-    prefix: &str,
+    meta: &BundleMeta,
+    frame: &[u64],
+    path: &Path,
+    overwrite: bool,
 ) -> Result<(), Error> {
     use std::io::Write;
 
-    let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
-    let filename = format!(
-        "bbundle_{}_{}_{}_{}_{}.zip",
-        prefix,
-        since_epoch.as_millis(),
-        width,
-        height,
-        max_iters
-    );
-    log::info!("name: {}", filename);
+    log::info!("name: {}", path.display());
 
-    let mut zip = zip::ZipWriter::new(File::create(Path::new(&filename))?);
+    let mut zip = zip::ZipWriter::new(naming::create_file(path, overwrite)?);
 
     let options = zip::write::FileOptions::default()
         .large_file(true)
         .compression_method(zip::CompressionMethod::Deflated);
-    let filename = format!("data.bin");
-    zip.start_file(filename, options)?;
+    zip.start_file("data.bin", options)?;
 
-    zip.write_all(&[0x01])?;
-    zip.write_all(&max_iters.to_le_bytes())?;
-    zip.write_all(&width.to_le_bytes())?;
-    zip.write_all(&height.to_le_bytes())?;
+    zip.write_all(&[FORMAT_VERSION])?;
+    zip.write_all(&meta.max_iters.to_le_bytes())?;
+    zip.write_all(&meta.width.to_le_bytes())?;
+    zip.write_all(&meta.height.to_le_bytes())?;
 
     let data_u8 = bytemuck::cast_slice(frame);
     zip.write_all(data_u8)?;
 
+    zip.start_file(MANIFEST_NAME, options)?;
+    zip.write_all(meta.to_manifest().as_bytes())?;
+
     zip.finish()?;
 
     Ok(())
@@ -1,96 +1,248 @@
-use num::Complex;
-use std::{borrow::Cow, str::FromStr};
-use wgpu::util::DeviceExt;
+use anyhow::{anyhow, ensure, Error};
+use std::borrow::Cow;
 use wgpu::*;
 
+use num::Complex;
+
+use crate::{
+    sampler::{
+        add_counts, Precision, RenderParams, SampleBatch, SampleStats, Sampler, MAX_CHANNELS,
+    },
+    shader::{self, ShaderReal},
+};
+
+/// Number of counters in the shader's `stats` buffer.
+const NUM_STATS: usize = 2;
+
+/// Inputs of the double-float self-check as (hi, lo) pairs, the factors of
+/// (1 + 2^-12)^2 and the terms of 1 + 2^-30.
+const DF_CHECK_INPUTS: [[f32; 2]; 4] = [
+    [1.0 + 1.0 / 4096.0, 0.0],
+    [1.0 + 1.0 / 4096.0, 0.0],
+    [1.0, 0.0],
+    [1.0 / 1073741824.0, 0.0],
+];
+
+/// Exact results of the self-check, 1 + 2^-11 + 2^-24 and 1 + 2^-30. An
+/// unfused fma or reassociated additions leave the low parts at 0.
+const DF_CHECK_EXPECTED: [[f32; 2]; 2] = [
+    [1.0 + 1.0 / 2048.0, 1.0 / 16777216.0],
+    [1.0, 1.0 / 1073741824.0],
+];
+
 pub struct GPUHandle {
     device: Device,
     queue: Queue,
     staging_buffer: Buffer,
     storage_buffer: Buffer,
-    prng_buffer: Buffer,
+    carry_buffer: Buffer,
     gpu_vars_buffer: Buffer,
+    chain_buffer: Buffer,
+    stats_buffer: Buffer,
     compute_pipeline: ComputePipeline,
     bind_group: BindGroup,
     width: u32,
     height: u32,
+    channels: u32,
+    trials: u32,
+    tiles: Vec<Tile>,
+}
+
+/// Screen rectangle binned by one dispatch. Frames whose counts don't fit
+/// in a single buffer are rendered one tile at a time.
+#[derive(Clone, Copy, Debug)]
+struct Tile {
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+}
+
+/// Splits the frame into tiles of at most `max_pixels` pixels, the first
+/// of which is the largest.
+fn split_tiles(width: u32, height: u32, max_pixels: u64) -> Vec<Tile> {
+    let tile_width = (width as u64).min(max_pixels).max(1) as u32;
+    let tile_height = (max_pixels / tile_width as u64).clamp(1, height as u64) as u32;
+    let mut tiles = vec![];
+    for y in (0..height).step_by(tile_height as usize) {
+        for x in (0..width).step_by(tile_width as usize) {
+            tiles.push(Tile {
+                x,
+                y,
+                width: tile_width.min(width - x),
+                height: tile_height.min(height - y),
+            });
+        }
+    }
+    tiles
+}
+
+/// Adds the counts of `tile` into the `width` x `height` frame. `lo` and
+/// `hi` hold the low words and the carries of counts that wrapped during
+/// the dispatch, tile row by tile row and one channel after the other.
+fn stitch_tile(
+    frame: &mut [u64],
+    width: u32,
+    height: u32,
+    tile: &Tile,
+    lo: &[u32],
+    hi: &[u32],
+) -> Result<(), Error> {
+    let counts = lo
+        .iter()
+        .zip(hi.iter())
+        .map(|(lo, hi)| (*hi as u64) << 32 | *lo as u64)
+        .collect::<Vec<_>>();
+
+    for (row, row_counts) in counts.chunks(tile.width as usize).enumerate() {
+        let ch = row / tile.height as usize;
+        let y = (tile.y as usize) + row % tile.height as usize;
+        let start = (ch * height as usize + y) * width as usize + tile.x as usize;
+        add_counts(
+            &mut frame[start..start + tile.width as usize],
+            row_counts.iter().copied(),
+        )?;
+    }
+    Ok(())
 }
 
+/// Layout of `GPUVars` in the shader. Coordinates are split by `split`.
 #[repr(C)]
 #[derive(bytemuck::Pod, bytemuck::Zeroable, Copy, Clone)]
 struct GPUVars {
+    lower_left: [f32; 4],
+    upper_right: [f32; 4],
+    zoom_lower_left: [f32; 4],
+    zoom_upper_right: [f32; 4],
+    julia_c: [f32; 4],
+    projection_x: [f32; 4],
+    projection_y: [f32; 4],
+    channel_iterations: [u32; MAX_CHANNELS],
     width: u32,
     height: u32,
     max_iterations: u32,
-    ll_re: f32,
-    ll_im: f32,
-    ur_re: f32,
-    ur_im: f32,
-    zoom_ll_re: f32,
-    zoom_ll_im: f32,
-    zoom_ur_re: f32,
-    zoom_ur_im: f32,
+    mode: u32,
+    num_channels: u32,
+    min_iterations: u32,
+    sampling: u32,
+    interior_check: u32,
+    seed_lo: u32,
+    seed_hi: u32,
+    tile_x: u32,
+    tile_y: u32,
+    tile_width: u32,
+    tile_height: u32,
+    first_tile: u32,
+    last_tile: u32,
+    julia: u32,
+    _pad: [u32; 3],
 }
 
-impl GPUHandle {
-    pub fn new(trialsx2: u32, width: u32, height: u32) -> Self {
-        assert!(trialsx2 % 6400 == 0);
+/// Layout of `Chain` in the shader. The buffer is only written by the GPU
+/// and starts zeroed, which marks every chain as not started. `reset`
+/// zeroes it again.
+#[repr(C)]
+#[derive(bytemuck::Pod, bytemuck::Zeroable, Copy, Clone)]
+struct GPUChain {
+    p1: [f32; 2],
+    p2: [f32; 2],
+    contrib: u32,
+    mask: u32,
+}
 
-        let (device, queue) = pollster::block_on(GPUHandle::initialize());
+/// Splits a coordinate into f32 hi and lo parts, `(re hi, re lo, im hi, im lo)`,
+/// which the shader adds back together in the precision it runs in.
+fn split(c: Complex<f64>) -> [f32; 4] {
+    let hi_lo = |x: f64| {
+        let hi = x as f32;
+        (hi, (x - hi as f64) as f32)
+    };
+    let (re_hi, re_lo) = hi_lo(c.re);
+    let (im_hi, im_lo) = hi_lo(c.im);
+    [re_hi, re_lo, im_hi, im_lo]
+}
 
-        let (
-            staging_buffer,
-            storage_buffer,
-            prng_buffer,
-            gpu_vars_buffer,
-            compute_pipeline,
-            bind_group,
-        ) = pollster::block_on(GPUHandle::setup_compute(&device, trialsx2, width, height));
+impl GPUHandle {
+    /// Builds the kernel for the formula, escape test and precision of
+    /// `params` and buffers for its frame size.
+    pub fn new(trials: u32, params: &RenderParams) -> Result<Self, Error> {
+        assert!(trials.is_multiple_of(6400));
 
-        GPUHandle {
+        let (device, queue, real_type) =
+            pollster::block_on(GPUHandle::initialize(params.precision))?;
+        let source = shader::source(real_type, params);
+
+        Ok(pollster::block_on(GPUHandle::setup_compute(
             device,
             queue,
-            staging_buffer,
-            storage_buffer,
-            gpu_vars_buffer,
-            prng_buffer,
-            compute_pipeline,
-            bind_group,
-            width,
-            height,
-        }
+            source,
+            trials,
+            params.width,
+            params.height,
+            params.num_channels(),
+        )))
     }
 
     async fn setup_compute(
-        device: &Device,
-        trialsx2: u32,
+        device: Device,
+        queue: Queue,
+        source: String,
+        trials: u32,
         width: u32,
         height: u32,
-    ) -> (Buffer, Buffer, Buffer, Buffer, ComputePipeline, BindGroup) {
+        channels: u32,
+    ) -> Self {
         // Loads the shader from WGSL
         let cs_module = device.create_shader_module(ShaderModuleDescriptor {
             label: None,
-            source: ShaderSource::Wgsl(Cow::Borrowed(include_str!("shader.wgsl"))),
+            source: ShaderSource::Wgsl(Cow::Owned(source)),
         });
 
+        let stats_size = (std::mem::size_of::<u32>() * NUM_STATS) as BufferAddress;
+
+        // the staging buffer holds two copies of the counts
+        let limits = device.limits();
+        let max_counts_size = (limits.max_storage_buffer_binding_size as BufferAddress)
+            .min((limits.max_buffer_size - stats_size) / 2);
+        let max_pixels = max_counts_size
+            / std::mem::size_of::<u32>() as BufferAddress
+            / channels as BufferAddress;
+        let tiles = split_tiles(width, height, max_pixels);
+        if tiles.len() > 1 {
+            log::info!(
+                "rendering {}x{} in {} tiles of up to {}x{}",
+                width,
+                height,
+                tiles.len(),
+                tiles[0].width,
+                tiles[0].height
+            );
+        }
+
+        let counts_size = std::mem::size_of::<u32>() as BufferAddress
+            * tiles[0].width as BufferAddress
+            * tiles[0].height as BufferAddress
+            * channels as BufferAddress;
+
+        // holds the low and high words of the counts followed by the stats
         let staging_buffer = device.create_buffer(&BufferDescriptor {
             label: None,
-            size: (std::mem::size_of::<u32>() as u32 * width * height) as BufferAddress,
+            size: 2 * counts_size + stats_size,
             usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
             mapped_at_creation: false,
         });
 
         let storage_buffer = device.create_buffer(&BufferDescriptor {
             label: Some("Storage Buffer"),
-            size: (std::mem::size_of::<u32>() as u32 * width * height) as BufferAddress,
+            size: counts_size,
             usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
             mapped_at_creation: false,
         });
 
-        let prng_buffer = device.create_buffer(&BufferDescriptor {
-            label: Some("PRNG Buffer"),
-            size: std::mem::size_of::<f32>() as BufferAddress * trialsx2 as BufferAddress,
-            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+        let carry_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Carry Buffer"),
+            size: counts_size,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
             mapped_at_creation: false,
         });
 
@@ -101,6 +253,20 @@ impl GPUHandle {
             mapped_at_creation: false,
         });
 
+        let chain_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Chain Buffer"),
+            size: std::mem::size_of::<GPUChain>() as BufferAddress * trials as BufferAddress,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+
+        let stats_buffer = device.create_buffer(&BufferDescriptor {
+            label: Some("Stats Buffer"),
+            size: stats_size,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+            mapped_at_creation: false,
+        });
+
         // A bind group defines how buffers are accessed by shaders.
         // It is to WebGPU what a descriptor set is to Vulkan.
         // `binding` here refers to the `binding` of a buffer in the shader (`layout(set = 0, binding = 0) buffer`).
@@ -125,29 +291,46 @@ impl GPUHandle {
                     binding: 0,
                     resource: storage_buffer.as_entire_binding(),
                 },
-                BindGroupEntry {
-                    binding: 1,
-                    resource: prng_buffer.as_entire_binding(),
-                },
                 BindGroupEntry {
                     binding: 2,
                     resource: gpu_vars_buffer.as_entire_binding(),
                 },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: chain_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 4,
+                    resource: stats_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 5,
+                    resource: carry_buffer.as_entire_binding(),
+                },
             ],
         });
 
-        (
+        GPUHandle {
+            device,
+            queue,
             staging_buffer,
             storage_buffer,
-            prng_buffer,
+            carry_buffer,
             gpu_vars_buffer,
+            chain_buffer,
+            stats_buffer,
             compute_pipeline,
             bind_group,
-        )
+            width,
+            height,
+            channels,
+            trials,
+            tiles,
+        }
     }
 
     #[cfg_attr(test, allow(dead_code))]
-    async fn initialize() -> (Device, Queue) {
+    async fn initialize(precision: Precision) -> Result<(Device, Queue, ShaderReal), Error> {
         // Instantiates instance of WebGPU
         let instance = Instance::default();
 
@@ -155,71 +338,104 @@ impl GPUHandle {
         let adapter = instance
             .request_adapter(&RequestAdapterOptions::default())
             .await
-            .unwrap();
+            .ok_or_else(|| anyhow!("no GPU adapter found, use --cpu to run on the CPU instead"))?;
 
         // `request_device` instantiates the feature specific connection to the GPU, defining some parameters,
         //  `features` being the available features.
 
-        let required_limits = {
-            let mut x = Limits::downlevel_defaults();
-            x.max_buffer_size = 2000000000;
-            x.max_storage_buffer_binding_size = 2000000000;
-            x.max_uniform_buffer_binding_size = 2000000000;
-            x
+        // larger frames are split into tiles that fit the buffer limits
+        let adapter_limits = adapter.limits();
+        let required_limits = Limits {
+            max_buffer_size: adapter_limits.max_buffer_size,
+            max_storage_buffer_binding_size: adapter_limits.max_storage_buffer_binding_size,
+            ..Limits::downlevel_defaults()
         };
 
-        adapter
+        // double precision uses native f64 when the adapter has it
+        let real_type = match precision {
+            Precision::Single => ShaderReal::F32,
+            Precision::Double if adapter.features().contains(Features::SHADER_F64) => {
+                ShaderReal::F64
+            }
+            Precision::Double => {
+                log::info!("adapter has no f64 support, emulating double precision");
+                ShaderReal::DoubleFloat
+            }
+        };
+        let required_features = if real_type == ShaderReal::F64 {
+            Features::SHADER_F64
+        } else {
+            Features::empty()
+        };
+
+        let (device, queue) = adapter
             .request_device(
                 &DeviceDescriptor {
                     label: None,
-                    required_features: Features::empty(),
+                    required_features,
                     required_limits,
                 },
                 None,
             )
-            .await
-            .unwrap()
+            .await?;
+
+        if real_type == ShaderReal::DoubleFloat {
+            check_double_float(&device, &queue).await?;
+        }
+
+        Ok((device, queue, real_type))
     }
 
     async fn execute_gpu(
         &mut self,
-        ll: Complex<f32>,
-        ur: Complex<f32>,
-        zoom_ll: Complex<f32>,
-        zoom_ur: Complex<f32>,
-        max_iterations: u32,
-        prng_data: Vec<f32>,
+        params: &RenderParams,
+        seed: u64,
+        tile: Tile,
+        first_tile: bool,
+        last_tile: bool,
     ) -> Option<Vec<u32>> {
         let gpu_vars = GPUVars {
+            lower_left: split(params.lower_left),
+            upper_right: split(params.upper_right),
+            zoom_lower_left: split(params.zoom_lower_left),
+            zoom_upper_right: split(params.zoom_upper_right),
+            julia_c: split(params.julia.unwrap_or_default()),
+            projection_x: params.projection[0].map(|m| m as f32),
+            projection_y: params.projection[1].map(|m| m as f32),
+            channel_iterations: channel_iterations(&params.channel_iters),
             width: self.width,
             height: self.height,
-            max_iterations,
-            ll_re: ll.re,
-            ll_im: ll.im,
-            ur_re: ur.re,
-            ur_im: ur.im,
-            zoom_ll_re: zoom_ll.re,
-            zoom_ll_im: zoom_ll.im,
-            zoom_ur_re: zoom_ur.re,
-            zoom_ur_im: zoom_ur.im,
+            max_iterations: params.max_iters,
+            mode: params.mode.as_u32(),
+            num_channels: params.num_channels(),
+            min_iterations: params.min_iters,
+            sampling: params.sampling.as_u32(),
+            interior_check: params.interior_check as u32,
+            seed_lo: seed as u32,
+            seed_hi: (seed >> 32) as u32,
+            tile_x: tile.x,
+            tile_y: tile.y,
+            tile_width: tile.width,
+            tile_height: tile.height,
+            first_tile: first_tile as u32,
+            last_tile: last_tile as u32,
+            julia: params.julia.is_some() as u32,
+            _pad: [0; 3],
         };
 
-        let zero_data = {
-            let mut v = vec![];
-            for _ in 0..self.storage_buffer.size() {
-                v.push(0);
-            }
-            v
-        };
+        let zero_data = vec![0u8; self.storage_buffer.size() as usize];
 
         self.queue.write_buffer(&self.storage_buffer, 0, &zero_data);
+        self.queue.write_buffer(&self.carry_buffer, 0, &zero_data);
+        self.queue.write_buffer(
+            &self.stats_buffer,
+            0,
+            &[0; std::mem::size_of::<u32>() * NUM_STATS],
+        );
 
         self.queue
             .write_buffer(&self.gpu_vars_buffer, 0, bytemuck::bytes_of(&gpu_vars));
 
-        self.queue
-            .write_buffer(&self.prng_buffer, 0, bytemuck::cast_slice(&prng_data));
-
         // A command encoder executes one or many pipelines.
         // It is to WebGPU what a command buffer is to Vulkan.
         let mut encoder = self
@@ -233,8 +449,8 @@ impl GPUHandle {
             cpass.set_pipeline(&self.compute_pipeline);
             cpass.set_bind_group(0, &self.bind_group, &[]);
             cpass.insert_debug_marker("compute buddhabrot iterations");
-            cpass.dispatch_workgroups(100, prng_data.len() as u32 / 64 / 100 / 2, 1);
-            // 6400/2 = 64 * 100 * (6400 /64/100/2)
+            cpass.dispatch_workgroups(100, self.trials / 64 / 100, 1);
+            // 6400 = 64 * 100 * (6400 /64/100)
         }
         // Sets adds copy operation to command encoder.
         // Will copy data from storage buffer on GPU to staging buffer on CPU.
@@ -245,6 +461,20 @@ impl GPUHandle {
             0,
             self.storage_buffer.size(),
         );
+        encoder.copy_buffer_to_buffer(
+            &self.carry_buffer,
+            0,
+            &self.staging_buffer,
+            self.storage_buffer.size(),
+            self.carry_buffer.size(),
+        );
+        encoder.copy_buffer_to_buffer(
+            &self.stats_buffer,
+            0,
+            &self.staging_buffer,
+            2 * self.storage_buffer.size(),
+            self.stats_buffer.size(),
+        );
 
         // Submits command encoder for processing
         self.queue.submit(Some(encoder.finish()));
@@ -282,17 +512,322 @@ impl GPUHandle {
             panic!("failed to run compute on gpu!")
         }
     }
+}
+
+/// Runs the double-float prelude on a product and a sum with known low
+/// parts. Compilers that don't fuse `fma`, such as some HLSL and Metal
+/// fast-math paths, would otherwise silently render in single precision.
+async fn check_double_float(device: &Device, queue: &Queue) -> Result<(), Error> {
+    let module = device.create_shader_module(ShaderModuleDescriptor {
+        label: Some("Double-float check"),
+        source: ShaderSource::Wgsl(Cow::Owned(shader::df_check_source())),
+    });
+    let pipeline = device.create_compute_pipeline(&ComputePipelineDescriptor {
+        label: None,
+        layout: None,
+        module: &module,
+        entry_point: "main",
+    });
+
+    // the inputs go through a buffer so the compiler can't fold the results
+    let size = std::mem::size_of::<[[f32; 2]; 6]>() as BufferAddress;
+    let buffer = device.create_buffer(&BufferDescriptor {
+        label: None,
+        size,
+        usage: BufferUsages::STORAGE | BufferUsages::COPY_DST | BufferUsages::COPY_SRC,
+        mapped_at_creation: false,
+    });
+    let staging_buffer = device.create_buffer(&BufferDescriptor {
+        label: None,
+        size,
+        usage: BufferUsages::MAP_READ | BufferUsages::COPY_DST,
+        mapped_at_creation: false,
+    });
+    let bind_group = device.create_bind_group(&BindGroupDescriptor {
+        label: None,
+        layout: &pipeline.get_bind_group_layout(0),
+        entries: &[BindGroupEntry {
+            binding: 0,
+            resource: buffer.as_entire_binding(),
+        }],
+    });
+    queue.write_buffer(&buffer, 0, bytemuck::cast_slice(&DF_CHECK_INPUTS));
+
+    let mut encoder = device.create_command_encoder(&CommandEncoderDescriptor { label: None });
+    {
+        let mut cpass = encoder.begin_compute_pass(&ComputePassDescriptor {
+            label: None,
+            timestamp_writes: None,
+        });
+        cpass.set_pipeline(&pipeline);
+        cpass.set_bind_group(0, &bind_group, &[]);
+        cpass.dispatch_workgroups(1, 1, 1);
+    }
+    encoder.copy_buffer_to_buffer(&buffer, 0, &staging_buffer, 0, size);
+    queue.submit(Some(encoder.finish()));
+
+    let buffer_slice = staging_buffer.slice(..);
+    let (sender, receiver) = flume::bounded(1);
+    buffer_slice.map_async(MapMode::Read, move |v| sender.send(v).unwrap());
+    device.poll(Maintain::wait()).panic_on_timeout();
+    receiver.recv_async().await??;
 
-    pub fn call(
+    let data = buffer_slice.get_mapped_range();
+    let results = bytemuck::cast_slice::<u8, [f32; 2]>(&data)[4..].to_vec();
+    drop(data);
+    staging_buffer.unmap();
+
+    check_df_results(&results)
+}
+
+/// Compares the results of the double-float self-check with the exact ones.
+fn check_df_results(results: &[[f32; 2]]) -> Result<(), Error> {
+    ensure!(
+        results == DF_CHECK_EXPECTED,
+        "the GPU loses the low parts of emulated double precision, got {results:?} instead of \
+         {DF_CHECK_EXPECTED:?}, its shader compiler doesn't fuse fma or reassociates additions. \
+         Use --precision single or --cpu instead"
+    );
+    Ok(())
+}
+
+fn channel_iterations(limits: &[u32]) -> [u32; MAX_CHANNELS] {
+    let mut v = [0; MAX_CHANNELS];
+    v[..limits.len()].copy_from_slice(limits);
+    v
+}
+
+impl Sampler for GPUHandle {
+    fn reset(&mut self) {
+        let mut encoder = self
+            .device
+            .create_command_encoder(&CommandEncoderDescriptor { label: None });
+        encoder.clear_buffer(&self.chain_buffer, 0, None);
+        self.queue.submit(Some(encoder.finish()));
+    }
+
+    fn accumulate(
         &mut self,
-        ll: Complex<f32>,
-        ur: Complex<f32>,
-        zoom_ll: Complex<f32>,
-        zoom_ur: Complex<f32>,
-        max_iterations: u32,
-        prng_data: Vec<f32>,
-    ) -> Vec<u32> {
-        pollster::block_on(self.execute_gpu(ll, ur, zoom_ll, zoom_ur, max_iterations, prng_data))
-            .unwrap()
+        params: &RenderParams,
+        batch: &SampleBatch,
+        frame: &mut [u64],
+    ) -> Result<SampleStats, Error> {
+        ensure!(
+            params.width == self.width
+                && params.height == self.height
+                && params.num_channels() == self.channels,
+            "render size {}x{}x{} does not match the GPU buffers ({}x{}x{})",
+            params.width,
+            params.height,
+            params.num_channels(),
+            self.width,
+            self.height,
+            self.channels
+        );
+        ensure!(
+            batch.trials == self.trials,
+            "expected batches of {} trials, got {}",
+            self.trials,
+            batch.trials
+        );
+
+        // every tile runs the same trials and bins its part of the orbits
+        let mut stats = SampleStats::default();
+        let buffer_len = self.storage_buffer.size() as usize / std::mem::size_of::<u32>();
+        let num_tiles = self.tiles.len();
+        for (n, tile) in self.tiles.clone().into_iter().enumerate() {
+            let result = pollster::block_on(self.execute_gpu(
+                params,
+                batch.seed,
+                tile,
+                n == 0,
+                n + 1 == num_tiles,
+            ))
+            .unwrap();
+            if n == 0 {
+                let tile_stats = &result[2 * buffer_len..];
+                stats = SampleStats {
+                    cardioid: tile_stats[0] as u64,
+                    bulb: tile_stats[1] as u64,
+                };
+            }
+
+            let tile_len = tile.width as usize * tile.height as usize * self.channels as usize;
+            stitch_tile(
+                frame,
+                self.width,
+                self.height,
+                &tile,
+                &result[..tile_len],
+                &result[buffer_len..buffer_len + tile_len],
+            )?;
+        }
+
+        Ok(stats)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks that the tiles cover every pixel of the frame exactly once
+    /// and stay within `max_pixels`.
+    fn check_cover(width: u32, height: u32, max_pixels: u64) {
+        let tiles = split_tiles(width, height, max_pixels);
+        let mut covered = 0u64;
+        for tile in &tiles {
+            assert!(tile.width > 0 && tile.height > 0);
+            assert!(tile.x + tile.width <= width && tile.y + tile.height <= height);
+            assert!(tile.width as u64 * tile.height as u64 <= max_pixels.max(1));
+            assert!(
+                tile.width as u64 * tile.height as u64
+                    <= tiles[0].width as u64 * tiles[0].height as u64
+            );
+            covered += tile.width as u64 * tile.height as u64;
+        }
+        assert_eq!(covered, width as u64 * height as u64);
+
+        // tiles are laid out in rows, so no two of them overlap
+        for (n, a) in tiles.iter().enumerate() {
+            for b in &tiles[n + 1..] {
+                let apart = a.x + a.width <= b.x
+                    || b.x + b.width <= a.x
+                    || a.y + a.height <= b.y
+                    || b.y + b.height <= a.y;
+                assert!(apart, "{a:?} overlaps {b:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn single_tile_when_frame_fits() {
+        let tiles = split_tiles(320, 240, 320 * 240);
+        assert_eq!(tiles.len(), 1);
+        assert_eq!((tiles[0].width, tiles[0].height), (320, 240));
+    }
+
+    #[test]
+    fn tiles_cover_frame() {
+        check_cover(320, 240, 320 * 100);
+        check_cover(321, 241, 1000);
+        check_cover(100, 7, 50);
+        check_cover(1, 1, 1);
+    }
+
+    #[test]
+    fn tiles_cover_print_size_frame() {
+        // the counts of 4 channels of this frame don't fit in 32 bits
+        let tiles = split_tiles(40000, 30000, 1 << 26);
+        let pixels = tiles
+            .iter()
+            .map(|t| t.width as u64 * t.height as u64)
+            .sum::<u64>();
+        assert_eq!(pixels, 40000 * 30000);
+        assert!(tiles
+            .iter()
+            .all(|t| t.width as u64 * t.height as u64 <= 1 << 26));
+    }
+
+    /// Stitches a tile whose counts are `channel * 1000 + y * 100 + x`,
+    /// with a carry of `channel` in the high words.
+    fn stitch(frame: &mut [u64], width: u32, height: u32, tile: Tile, channels: u32) {
+        let mut lo = vec![];
+        let mut hi = vec![];
+        for ch in 0..channels {
+            for y in tile.y..tile.y + tile.height {
+                for x in tile.x..tile.x + tile.width {
+                    lo.push(ch * 1000 + y * 100 + x);
+                    hi.push(ch);
+                }
+            }
+        }
+        stitch_tile(frame, width, height, &tile, &lo, &hi).unwrap();
+    }
+
+    #[test]
+    fn stitched_tiles_fill_the_frame() {
+        let (width, height, channels) = (7, 5, 3);
+        // 7x2 tiles with a last tile of one row, and 5x1 and 2x1 tiles
+        for max_pixels in [14, 5] {
+            let tiles = split_tiles(width, height, max_pixels);
+            assert!(tiles.len() > 1);
+            let mut frame = vec![0; (width * height * channels) as usize];
+            for tile in tiles {
+                stitch(&mut frame, width, height, tile, channels);
+            }
+
+            for ch in 0..channels {
+                for y in 0..height {
+                    for x in 0..width {
+                        let expected = (ch as u64) << 32 | (ch * 1000 + y * 100 + x) as u64;
+                        let n = ((ch * height + y) * width + x) as usize;
+                        assert_eq!(frame[n], expected, "channel {ch} at {x},{y}");
+                    }
+                }
+            }
+        }
+        assert_eq!(split_tiles(width, height, 14).last().unwrap().height, 1);
+    }
+
+    #[test]
+    fn stitching_adds_to_the_frame() {
+        let tile = Tile {
+            x: 1,
+            y: 1,
+            width: 2,
+            height: 1,
+        };
+        let mut frame = vec![1; 3 * 2 * 2];
+        stitch_tile(&mut frame, 3, 2, &tile, &[5, 6, 7, 8], &[0, 1, 0, 0]).unwrap();
+        assert_eq!(frame, vec![1, 1, 1, 1, 6, (1 << 32) + 7, 1, 1, 1, 1, 8, 9]);
+
+        let err = stitch_tile(&mut frame, 3, 2, &tile, &[0, 0, 0, 0], &[u32::MAX; 4]);
+        assert!(err.is_err());
+    }
+
+    #[test]
+    fn double_float_check_needs_the_low_parts() {
+        assert!(check_df_results(&DF_CHECK_EXPECTED).is_ok());
+
+        // an unfused fma rounds the product and loses its error term
+        let [mul, add] = DF_CHECK_EXPECTED;
+        let unfused = [[mul[0], 0.0], add];
+        let err = check_df_results(&unfused).unwrap_err().to_string();
+        assert!(
+            err.contains("fma") && err.contains("--precision single"),
+            "{err}"
+        );
+
+        // reassociated additions lose the low part of the sum
+        assert!(check_df_results(&[mul, [add[0], 0.0]]).is_err());
+    }
+
+    #[test]
+    fn double_float_check_runs_on_the_adapter() {
+        let instance = Instance::default();
+        let Some(adapter) =
+            pollster::block_on(instance.request_adapter(&RequestAdapterOptions::default()))
+        else {
+            eprintln!("no GPU adapter, skipping the double-float check");
+            return;
+        };
+        let (device, queue) = pollster::block_on(adapter.request_device(
+            &DeviceDescriptor {
+                label: None,
+                required_features: Features::empty(),
+                required_limits: Limits::downlevel_defaults(),
+            },
+            None,
+        ))
+        .unwrap();
+
+        // software adapters such as llvmpipe do lose the low parts, which
+        // must be reported rather than fail some other way
+        if let Err(err) = pollster::block_on(check_double_float(&device, &queue)) {
+            let err = err.to_string();
+            assert!(err.contains("loses the low parts"), "{err}");
+            eprintln!("{}: {err}", adapter.get_info().name);
+        }
     }
 }
@@ -1,10 +1,20 @@
-use anyhow::Error;
+use std::path::PathBuf;
 
-mod fractal;
-mod gpu;
+use anyhow::{ensure, Error};
+
+use buddhabrot_wgpu::{
+    formula::Formula,
+    fractal,
+    naming::{NameFields, OutputName, BUNDLE_TEMPLATE},
+    projection::{self, PlaneRotation, Projection, Z_PLANE},
+    sampler::{
+        EscapeTest, Precision, RenderMode, RenderParams, SamplingMethod, DEFAULT_ESCAPE_RADIUS,
+    },
+};
 
 use clap::Parser;
 use num::Complex;
+use rand::Rng;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -13,6 +23,20 @@ struct Args {
     #[arg(short, long)]
     name: String,
 
+    /// Output file name template with the placeholders {name}, {run},
+    /// {seed}, {width}, {height}, {iterations}, {timestamp} and {ext}. Must
+    /// contain {run} or {timestamp} so that every zip gets its own file
+    #[arg(short, long, default_value = BUNDLE_TEMPLATE)]
+    output: String,
+
+    /// Directory the bbundle files are written to
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// Replace output files that already exist instead of failing
+    #[arg(long)]
+    overwrite: bool,
+
     /// Width of output in pixels
     #[arg(long, default_value_t = 320)]
     width: u32,
@@ -25,73 +49,218 @@ struct Args {
     #[arg(short, long, default_value_t = 1000)]
     iterations: u32,
 
-    /// Number of parallel trials to run on the GPU each iteration
+    /// Comma separated iteration limits, one count channel per limit (at
+    /// most 4), e.g. 5000,500,50 for a Nebulabrot. Overrides --iterations
+    #[arg(long, value_delimiter = ',', conflicts_with = "iterations")]
+    channel_iterations: Option<Vec<u32>>,
+
+    /// Orbits shorter than this many iterations are not counted
+    #[arg(long, default_value_t = 0)]
+    min_iterations: u32,
+
+    /// Which orbits to accumulate
+    #[arg(long, value_enum, default_value_t = RenderMode::Buddhabrot)]
+    mode: RenderMode,
+
+    /// How trials are sampled, metropolis speeds up small zoom windows
+    #[arg(long, value_enum, default_value_t = SamplingMethod::Uniform)]
+    sampling: SamplingMethod,
+
+    /// Iteration to render: mandelbrot, multibrot:<power> with an integer
+    /// or real power up to 64, burning-ship, tricorn (or mandelbar) or
+    /// celtic
+    #[arg(long, default_value_t = Formula::Mandelbrot)]
+    formula: Formula,
+
+    /// Test for when an orbit has escaped, some formulas look better with
+    /// a test other than the circle
+    #[arg(long, value_enum, default_value_t = EscapeTest::Circle)]
+    escape_test: EscapeTest,
+
+    /// Escape radius of the escape test
+    #[arg(long, default_value_t = DEFAULT_ESCAPE_RADIUS)]
+    escape_radius: f64,
+
+    /// Real part of c for Julia mode, where the sample window holds the
+    /// starting points of the orbits instead of c
+    #[arg(long, allow_hyphen_values = true, requires = "julia_im")]
+    julia_re: Option<f64>,
+
+    /// Imaginary part of c for Julia mode
+    #[arg(long, allow_hyphen_values = true, requires = "julia_re")]
+    julia_im: Option<f64>,
+
+    /// Comma separated rows of the 2x4 matrix projecting the orbits'
+    /// (z.re, z.im, c.re, c.im) onto the screen, e.g. 1,0,0,0,0,0,1,0 for
+    /// the z.re/c.re plane. Defaults to the z plane
+    #[arg(long, value_delimiter = ',', allow_hyphen_values = true)]
+    projection: Option<Vec<f64>>,
+
+    /// Rotation of the orbit space before the projection, e.g. zr-cr=30
+    /// for 30 degrees in the plane of z.re and c.re. The axes are zr, zi,
+    /// cr and ci. Can be repeated
+    #[arg(long, allow_hyphen_values = true)]
+    rotate: Vec<PlaneRotation>,
+
+    /// Rotation added for every run to animate the projection, run n is
+    /// rotated n - 1 times, so each zip is one frame. Can be repeated
+    #[arg(long, allow_hyphen_values = true)]
+    rotate_step: Vec<PlaneRotation>,
+
+    /// Run the full escape test for samples in the main cardioid and
+    /// period-2 bulb instead of classifying them analytically. Only the
+    /// mandelbrot formula outside Julia mode with an escape radius of at
+    /// least 2 (2√2 for the manhattan test) has this test
+    #[arg(long)]
+    no_interior_check: bool,
+
+    /// Precision orbits are iterated in, double is needed for zoom windows
+    /// narrower than about 1e-5
+    #[arg(long, value_enum, default_value_t = Precision::Single)]
+    precision: Precision,
+
+    /// Number of parallel trials to run on the GPU each iteration, rounded
+    /// up to a multiple of 6400
     #[arg(long, default_value_t = 6400*10)]
     gpu_trials: u32,
 
+    /// Run the trials on the CPU instead of the GPU
+    #[arg(long)]
+    cpu: bool,
+
+    /// Number of worker threads for the CPU backend (defaults to all cores)
+    #[arg(long, requires = "cpu")]
+    threads: Option<usize>,
+
+    /// Seed for the sample stream, random if not given. The counts of each
+    /// zip only depend on the seed, the run index and the render settings
+    #[arg(long)]
+    seed: Option<u64>,
+
+    /// Run index of the first zip file, to continue a seeded render
+    #[arg(long, default_value_t = 1)]
+    first_run: u64,
+
     /// Number of times to run per zip file
     #[arg(short, long, default_value_t = 10)]
     runs_per_zip: u32,
 
     /// Real part of full image lower left corner
     #[arg(long, default_value_t = -2.25, allow_hyphen_values = true)]
-    lower_left_re: f32,
+    lower_left_re: f64,
 
     /// Imaginary part of full image lower left corner
     #[arg(long, default_value_t = -1.5, allow_hyphen_values = true)]
-    lower_left_im: f32,
+    lower_left_im: f64,
 
     /// Real part of full image upper right corner
     #[arg(long, default_value_t = 1.0, allow_hyphen_values = true)]
-    upper_right_re: f32,
+    upper_right_re: f64,
 
     /// Imaginary part of full image upper right corner
     #[arg(long, default_value_t = 1.5, allow_hyphen_values = true)]
-    upper_right_im: f32,
+    upper_right_im: f64,
 
     /// Real part of zoom lower left corner
     #[arg(long, default_value_t = -2.25, allow_hyphen_values = true)]
-    zoom_lower_left_re: f32,
+    zoom_lower_left_re: f64,
 
     /// Imaginary part of zoom lower left corner
     #[arg(long, default_value_t = -1.5, allow_hyphen_values = true)]
-    zoom_lower_left_im: f32,
+    zoom_lower_left_im: f64,
 
     /// Real part of zoom upper right corner
     #[arg(long, default_value_t = 1.0, allow_hyphen_values = true)]
-    zoom_upper_right_re: f32,
+    zoom_upper_right_re: f64,
 
     /// Imaginary part of zoom upper right corner
     #[arg(long, default_value_t = 1.5, allow_hyphen_values = true)]
-    zoom_upper_right_im: f32,
+    zoom_upper_right_im: f64,
+}
+
+/// Projection of run `run`, with the rotation steps applied `run - 1` times.
+fn run_projection(args: &Args, base: Projection, run: u64) -> Projection {
+    let steps = args
+        .rotate_step
+        .iter()
+        .map(|r| r.times(run.saturating_sub(1) as f64));
+    let rotations = args.rotate.iter().copied().chain(steps).collect::<Vec<_>>();
+    projection::rotate(base, &rotations)
 }
 
 fn main() -> Result<(), Error> {
     let args = Args::parse();
 
-    env_logger::init();
+    // warnings show without RUST_LOG
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
 
     let ll = Complex::new(args.lower_left_re, args.lower_left_im);
     let ur = Complex::new(args.upper_right_re, args.upper_right_im);
     let llz = Complex::new(args.zoom_lower_left_re, args.zoom_lower_left_im);
     let urz = Complex::new(args.zoom_upper_right_re, args.zoom_upper_right_im);
-    let mut buddhabrot_gpu = fractal::BuddhabrotGPU::new(
-        args.width,
-        args.height,
-        args.iterations,
-        args.gpu_trials,
-        ll,
-        ur,
-        llz,
-        urz,
+    let julia = args
+        .julia_re
+        .zip(args.julia_im)
+        .map(|(re, im)| Complex::new(re, im));
+    let base_projection = match &args.projection {
+        Some(m) => {
+            ensure!(m.len() == 8, "--projection needs 8 values, got {}", m.len());
+            [[m[0], m[1], m[2], m[3]], [m[4], m[5], m[6], m[7]]]
+        }
+        None => Z_PLANE,
+    };
+    let output = OutputName::new(args.output_dir.as_deref(), &args.output)?;
+    ensure!(
+        output.uses("run") || output.uses("timestamp"),
+        "--output needs {{run}} or {{timestamp}}, otherwise every zip overwrites the last"
     );
+    let backend = if args.cpu {
+        let threads = match args.threads {
+            Some(t) => t,
+            None => std::thread::available_parallelism()?.get(),
+        };
+        fractal::BackendKind::Cpu { threads }
+    } else {
+        fractal::BackendKind::Gpu
+    };
+    let channel_iters = args
+        .channel_iterations
+        .clone()
+        .unwrap_or(vec![args.iterations]);
+    let params = RenderParams {
+        width: args.width,
+        height: args.height,
+        max_iters: *channel_iters.iter().max().unwrap_or(&args.iterations),
+        channel_iters,
+        min_iters: args.min_iterations,
+        sampling: args.sampling,
+        formula: args.formula,
+        escape_test: args.escape_test,
+        escape_radius: args.escape_radius,
+        interior_check: !args.no_interior_check
+            && args.formula.has_interior_check()
+            && julia.is_none()
+            && args.escape_test.contains_disc(args.escape_radius),
+        precision: args.precision,
+        mode: args.mode,
+        lower_left: ll,
+        upper_right: ur,
+        zoom_lower_left: llz,
+        zoom_upper_right: urz,
+        julia,
+        projection: run_projection(&args, base_projection, args.first_run),
+    };
+    let seed = args.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    log::info!("seed: {seed}");
+    let mut buddhabrot_gpu = fractal::BuddhabrotGPU::new(params, args.gpu_trials, seed, backend)?;
 
-    let mut run_count = 1;
+    let mut run_count = args.first_run;
 
     loop {
+        buddhabrot_gpu.set_projection(run_projection(&args, base_projection, run_count));
+        buddhabrot_gpu.start_run(run_count);
         for trial in 0..args.runs_per_zip {
-            buddhabrot_gpu.update();
-            //buddhabrot_gpu.dump_stats();
+            buddhabrot_gpu.update()?;
             println!(
                 "Trial: {}/{}, Run: {}",
                 trial + 1,
@@ -99,12 +268,12 @@ fn main() -> Result<(), Error> {
                 run_count
             );
         }
+        buddhabrot_gpu.dump_stats();
         log::info!("Writing zip number {}", run_count);
-        buddhabrot_gpu.dump_to_file(&args.name)?;
-        buddhabrot_gpu.reset();
+        let meta = buddhabrot_gpu.metadata();
+        let path = output.path(&NameFields::from_meta(&meta, Some(&args.name), "zip"))?;
+        buddhabrot_gpu.dump_to_file(&path, args.overwrite)?;
 
         run_count += 1;
     }
-
-    Ok(())
 }
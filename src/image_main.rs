@@ -1,38 +1,340 @@
-use anyhow::Error;
+use std::path::PathBuf;
 
-mod bundle;
-mod fractal;
-mod gpu;
+use anyhow::{bail, ensure, Error};
+
+use buddhabrot_wgpu::{
+    bundle::{self, BundleMeta},
+    naming::{NameFields, OutputName, IMAGE_TEMPLATE},
+    npy,
+    palette::Palette,
+    pfm,
+    png::{self, ChannelSource, PngFormat},
+    tone::{ToneCurve, ToneMap},
+};
 
 use clap::Parser;
 
 use glob::glob;
 
-mod png;
+/// File format of the output image.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum ImageFormat {
+    /// 16-bit RGB PNG
+    Png,
+    /// 16-bit greyscale PNG, for single channel bundles without a palette
+    Grey16,
+    /// 8-bit RGB PNG with dithering
+    Png8,
+    /// Portable Float Map with 32-bit float samples, greyscale for single
+    /// channel bundles without a palette
+    Pfm,
+    /// NumPy array of the raw counts of the --bundle-files, with the render
+    /// settings in a JSON file next to it
+    Npy,
+}
+
+impl ImageFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            Self::Png | Self::Grey16 | Self::Png8 => "png",
+            Self::Pfm => "pfm",
+            Self::Npy => "npy",
+        }
+    }
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     /// File glob of bbundle files to include in output
+    #[arg(
+        short,
+        long,
+        required_unless_present_any = ["red", "green", "blue"],
+        conflicts_with_all = ["red", "green", "blue"]
+    )]
+    bundle_files: Option<String>,
+
+    /// File glob of bbundle files for the red channel, composed with the
+    /// --green and --blue bundles into one image
+    #[arg(long)]
+    red: Option<String>,
+
+    /// Tone curve of the red channel, defaults to --curve
+    #[arg(long, value_enum)]
+    red_curve: Option<ToneCurve>,
+
+    /// Brightness factor of the red channel
+    #[arg(long, default_value_t = 1.0)]
+    red_weight: f64,
+
+    /// File glob of bbundle files for the green channel
+    #[arg(long)]
+    green: Option<String>,
+
+    /// Tone curve of the green channel, defaults to --curve
+    #[arg(long, value_enum)]
+    green_curve: Option<ToneCurve>,
+
+    /// Brightness factor of the green channel
+    #[arg(long, default_value_t = 1.0)]
+    green_weight: f64,
+
+    /// File glob of bbundle files for the blue channel
+    #[arg(long)]
+    blue: Option<String>,
+
+    /// Tone curve of the blue channel, defaults to --curve
+    #[arg(long, value_enum)]
+    blue_curve: Option<ToneCurve>,
+
+    /// Brightness factor of the blue channel
+    #[arg(long, default_value_t = 1.0)]
+    blue_weight: f64,
+
+    /// Combine bundles whose render settings differ instead of failing
+    #[arg(long)]
+    force: bool,
+
+    /// Curve mapping counts to brightness between the black and white points
+    #[arg(long, value_enum, default_value_t = ToneCurve::Linear)]
+    curve: ToneCurve,
+
+    /// Gamma of the gamma curve
+    #[arg(long, default_value_t = 2.2)]
+    gamma: f64,
+
+    /// Strength of the log and asinh curves, higher brightens faint areas
+    #[arg(long, default_value_t = 1000.0)]
+    stretch: f64,
+
+    /// Percentile of the non-zero counts that becomes black
+    #[arg(long, default_value_t = 0.0)]
+    black_point: f64,
+
+    /// Percentile of the non-zero counts that becomes white, e.g. 99.9 to
+    /// keep a few hot pixels from darkening the rest
+    #[arg(long, default_value_t = 100.0)]
+    white_point: f64,
+
+    /// Colours single channel bundles with a palette (grey, fire, ice,
+    /// magma or viridis) or a gradient file of `position #rrggbb` lines,
+    /// e.g. `0.5 #c83200`
+    #[arg(long, value_name = "NAME|FILE", conflicts_with_all = ["red", "green", "blue"])]
+    palette: Option<String>,
+
+    /// File format of the output image
+    #[arg(long, value_enum, default_value_t = ImageFormat::Png)]
+    format: ImageFormat,
+
+    /// Write the raw counts instead of the tone mapped levels, for --format
+    /// pfm
+    #[arg(long)]
+    raw: bool,
+
+    /// Output file name template with the placeholders {name}, {run},
+    /// {seed}, {width}, {height}, {iterations}, {timestamp} and {ext} (the
+    /// format's extension), taken from the bundles
+    #[arg(short, long, default_value = IMAGE_TEMPLATE)]
+    output: String,
+
+    /// Directory the output file is written to
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// Replace output files that already exist instead of failing
+    #[arg(long)]
+    overwrite: bool,
+
+    /// Value of the {name} placeholder
     #[arg(short, long)]
-    bundle_files: String,
+    name: Option<String>,
+}
+
+fn glob_files(pattern: &str) -> Result<Vec<PathBuf>, Error> {
+    let bundle_files = glob(pattern)
+        .expect("Failed to read glob pattern")
+        .collect::<Result<Vec<_>, _>>()?;
+
+    log::info!("bundle files: {:?}", bundle_files);
+
+    Ok(bundle_files)
+}
+
+/// Bundles summed into one channel of a composed image.
+struct ChannelSet {
+    name: String,
+    meta: BundleMeta,
+    data: Vec<u64>,
+    tone: ToneMap,
+    weight: f64,
+}
+
+/// Sums the bundles of each of `--red`, `--green` and `--blue` and checks
+/// that they cover the same view.
+fn channel_sets(args: &Args, tone: &ToneMap) -> Result<[Option<ChannelSet>; 3], Error> {
+    let channels = [
+        ("red", &args.red, args.red_curve, args.red_weight),
+        ("green", &args.green, args.green_curve, args.green_weight),
+        ("blue", &args.blue, args.blue_curve, args.blue_weight),
+    ];
+
+    let sets = channels.map(|(color, pattern, curve, weight)| {
+        let Some(pattern) = pattern else {
+            return Ok(None);
+        };
+        ensure!(weight >= 0.0, "--{color}-weight must not be negative");
+        let (meta, data) = bundle::gather_data(glob_files(pattern)?, args.force)?;
+        if meta.num_channels() > 1 {
+            log::warn!(
+                "only the first of the {} channels of the --{color} bundles is used",
+                meta.num_channels()
+            );
+        }
+        Ok(Some(ChannelSet {
+            name: format!("--{color} bundles {pattern}"),
+            meta,
+            data,
+            tone: ToneMap {
+                curve: curve.unwrap_or(tone.curve),
+                ..*tone
+            },
+            weight,
+        }))
+    });
+    let [red, green, blue] = sets;
+    let sets = [red?, green?, blue?];
+
+    let mut present = sets.iter().flatten();
+    let first = present.next().unwrap();
+    for set in present {
+        bundle::check_same_view(&set.name, &first.name, &first.meta, &set.meta, args.force)?;
+    }
+
+    Ok(sets)
 }
 
 fn main() -> Result<(), Error> {
     let args = Args::parse();
 
-    env_logger::init();
+    // warnings, such as forced mismatched settings, show without RUST_LOG
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
 
-    let bundle_files = glob(&args.bundle_files)
-        .expect("Failed to read glob pattern")
-        .into_iter()
-        .collect::<Result<Vec<_>, _>>()?;
+    let tone = ToneMap::new(
+        args.curve,
+        args.gamma,
+        args.stretch,
+        args.black_point,
+        args.white_point,
+    )?;
+    let palette = args.palette.as_deref().map(Palette::from_arg).transpose()?;
+    let output = OutputName::new(args.output_dir.as_deref(), &args.output)?;
+    let ext = args.format.extension();
 
-    log::info!("bundle files: {:?}", bundle_files);
+    ensure!(
+        !args.raw || args.format == ImageFormat::Pfm,
+        "--raw needs --format pfm"
+    );
+
+    if args.format == ImageFormat::Npy {
+        let Some(pattern) = &args.bundle_files else {
+            bail!("--format npy needs --bundle-files");
+        };
+        let (meta, data) = bundle::gather_data(glob_files(pattern)?, args.force)?;
+        let path = output.path(&NameFields::from_meta(&meta, args.name.as_deref(), ext))?;
+        npy::write_npy(&path, &meta, &data, args.overwrite)?;
+        log::info!("wrote {}", path.display());
+        return Ok(());
+    }
+
+    let (meta, grey, pixels) = match &args.bundle_files {
+        Some(pattern) => {
+            let (meta, data) = bundle::gather_data(glob_files(pattern)?, args.force)?;
+            let channels = meta.num_channels();
+            let grey = channels == 1 && palette.is_none();
+
+            let pixels = if args.raw {
+                let channel_size = meta.width as usize * meta.height as usize;
+                (0..channel_size)
+                    .map(|n| {
+                        [0, 1, 2]
+                            .map(|ch| data.get(ch * channel_size + n).map_or(0.0, |c| *c as f64))
+                    })
+                    .collect()
+            } else {
+                png::rgb_levels(
+                    meta.width,
+                    meta.height,
+                    channels,
+                    &data,
+                    &tone,
+                    palette.as_ref(),
+                )?
+            };
+            (meta, grey, pixels)
+        }
+        None => {
+            let sets = channel_sets(&args, &tone)?;
+            let first = sets.iter().flatten().next().unwrap();
+            let pixel_count = first.meta.width as usize * first.meta.height as usize;
 
-    let (width, height, _iterations, data) = bundle::gather_data(bundle_files)?;
+            let pixels = if args.raw {
+                (0..pixel_count)
+                    .map(|n| {
+                        [0, 1, 2].map(|ch| sets[ch].as_ref().map_or(0.0, |s| s.data[n] as f64))
+                    })
+                    .collect()
+            } else {
+                let sources = sets.each_ref().map(|set| {
+                    set.as_ref().map(|s| ChannelSource {
+                        counts: &s.data,
+                        tone: s.tone,
+                        weight: s.weight,
+                    })
+                });
+                png::compose_levels(pixel_count, sources)
+            };
+            (first.meta.clone(), false, pixels)
+        }
+    };
 
-    png::write_png(width, height, &data)?;
+    let path = output.path(&NameFields::from_meta(&meta, args.name.as_deref(), ext))?;
+    let (width, height) = (meta.width, meta.height);
+    match args.format {
+        ImageFormat::Png => png::write_png(
+            &path,
+            width,
+            height,
+            &pixels,
+            PngFormat::Rgb16,
+            args.overwrite,
+        )?,
+        ImageFormat::Png8 => png::write_png(
+            &path,
+            width,
+            height,
+            &pixels,
+            PngFormat::Rgb8,
+            args.overwrite,
+        )?,
+        ImageFormat::Grey16 => {
+            ensure!(
+                grey,
+                "grey16 needs single channel bundles without a palette"
+            );
+            png::write_png(
+                &path,
+                width,
+                height,
+                &pixels,
+                PngFormat::Grey16,
+                args.overwrite,
+            )?
+        }
+        ImageFormat::Pfm => pfm::write_pfm(&path, width, height, &pixels, grey, args.overwrite)?,
+        ImageFormat::Npy => unreachable!(),
+    }
+    log::info!("wrote {}", path.display());
 
     Ok(())
 }
@@ -0,0 +1,14 @@
+pub mod bundle;
+pub mod cpu;
+pub mod formula;
+pub mod fractal;
+pub mod gpu;
+pub mod naming;
+pub mod npy;
+pub mod palette;
+pub mod pfm;
+pub mod png;
+pub mod projection;
+pub mod sampler;
+pub mod shader;
+pub mod tone;
@@ -1,15 +1,16 @@
+use std::path::PathBuf;
+
 use anyhow::Error;
 
-mod bundle;
-mod fractal;
-mod gpu;
+use buddhabrot_wgpu::{
+    bundle, fractal,
+    naming::{NameFields, OutputName, BUNDLE_TEMPLATE},
+};
 
 use clap::Parser;
 
 use glob::glob;
 
-mod png;
-
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -17,26 +18,48 @@ struct Args {
     #[arg(short, long)]
     bundle_files: String,
 
+    /// Combine bundles whose render settings differ instead of failing
+    #[arg(long)]
+    force: bool,
+
     /// Name of prefix on bbundle output file
     #[arg(short, long)]
     name: String,
+
+    /// Output file name template with the placeholders {name}, {run},
+    /// {seed}, {width}, {height}, {iterations}, {timestamp} and {ext}. Run
+    /// and seed are only known when a single bundle is merged
+    #[arg(short, long, default_value = BUNDLE_TEMPLATE)]
+    output: String,
+
+    /// Directory the merged bundle is written to
+    #[arg(long)]
+    output_dir: Option<PathBuf>,
+
+    /// Replace output files that already exist instead of failing
+    #[arg(long)]
+    overwrite: bool,
 }
 
 fn main() -> Result<(), Error> {
     let args = Args::parse();
 
-    env_logger::init();
+    // warnings, such as bundles sharing a seed and run or forced mismatched
+    // settings, show without RUST_LOG
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let output = OutputName::new(args.output_dir.as_deref(), &args.output)?;
 
     let bundle_files = glob(&args.bundle_files)
         .expect("Failed to read glob pattern")
-        .into_iter()
         .collect::<Result<Vec<_>, _>>()?;
 
     log::info!("bundle files: {:?}", bundle_files);
 
-    let (width, height, iterations, data) = bundle::gather_data(bundle_files)?;
+    let (meta, data) = bundle::gather_data(bundle_files, args.force)?;
 
-    fractal::dump_to_file(width, height, iterations, &data, &args.name)?;
+    let path = output.path(&NameFields::from_meta(&meta, Some(&args.name), "zip"))?;
+    fractal::dump_to_file(&meta, &data, &path, args.overwrite)?;
 
     Ok(())
 }
@@ -0,0 +1,277 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use anyhow::{anyhow, bail, Error};
+
+use crate::bundle::BundleMeta;
+
+/// Placeholders an output file name template can use.
+pub const PLACEHOLDERS: [&str; 8] = [
+    "name",
+    "run",
+    "seed",
+    "width",
+    "height",
+    "iterations",
+    "timestamp",
+    "ext",
+];
+
+/// Default template of bundles written by `gpu` and `merge`.
+pub const BUNDLE_TEMPLATE: &str = "bbundle_{name}_{timestamp}_{width}_{height}_{iterations}.zip";
+
+/// Default template of files written by `image`.
+pub const IMAGE_TEMPLATE: &str = "{timestamp}.{ext}";
+
+/// Values of the placeholders for one output file. Placeholders whose value
+/// is missing are an error when the template uses them.
+pub struct NameFields<'a> {
+    pub name: Option<&'a str>,
+    pub run: Option<u64>,
+    pub seed: Option<u64>,
+    pub width: u32,
+    pub height: u32,
+    pub iterations: u32,
+    pub ext: &'a str,
+}
+
+impl<'a> NameFields<'a> {
+    /// Fields of a file holding the counts described by `meta`.
+    pub fn from_meta(meta: &BundleMeta, name: Option<&'a str>, ext: &'a str) -> Self {
+        Self {
+            name,
+            run: meta.run,
+            seed: meta.seed,
+            width: meta.width,
+            height: meta.height,
+            iterations: meta.max_iters,
+            ext,
+        }
+    }
+
+    fn value(&self, placeholder: &str) -> Result<String, Error> {
+        let missing = || anyhow!("{{{placeholder}}} has no value for this output");
+        Ok(match placeholder {
+            "name" => self.name.ok_or_else(missing)?.to_string(),
+            "run" => self.run.ok_or_else(missing)?.to_string(),
+            "seed" => self.seed.ok_or_else(missing)?.to_string(),
+            "width" => self.width.to_string(),
+            "height" => self.height.to_string(),
+            "iterations" => self.iterations.to_string(),
+            "timestamp" => SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_millis()
+                .to_string(),
+            "ext" => self.ext.to_string(),
+            _ => unreachable!(),
+        })
+    }
+}
+
+/// Splits a template into literal text and placeholder names.
+fn parse(template: &str) -> Result<Vec<(bool, &str)>, Error> {
+    let mut parts = vec![];
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else {
+            bail!("unclosed placeholder in output name {template}");
+        };
+        let placeholder = &rest[start + 1..start + len];
+        if !PLACEHOLDERS.contains(&placeholder) {
+            bail!(
+                "unknown placeholder {{{placeholder}}} in output name {template}, expected one of {}",
+                PLACEHOLDERS.join(", ")
+            );
+        }
+        parts.push((false, &rest[..start]));
+        parts.push((true, placeholder));
+        rest = &rest[start + len + 1..];
+    }
+    parts.push((false, rest));
+    Ok(parts)
+}
+
+/// Where a binary writes its output: a directory and a file name template
+/// such as `frame_{run}.zip`, which may itself contain directories.
+#[derive(Clone, Debug)]
+pub struct OutputName {
+    dir: PathBuf,
+    template: String,
+}
+
+impl OutputName {
+    /// Checks the template's placeholders. `dir` defaults to the current
+    /// directory.
+    pub fn new(dir: Option<&Path>, template: &str) -> Result<Self, Error> {
+        parse(template)?;
+        Ok(Self {
+            dir: dir.map(Path::to_path_buf).unwrap_or_default(),
+            template: template.to_string(),
+        })
+    }
+
+    /// Whether the template uses `placeholder`.
+    pub fn uses(&self, placeholder: &str) -> bool {
+        parse(&self.template)
+            .unwrap()
+            .iter()
+            .any(|(is_placeholder, s)| *is_placeholder && *s == placeholder)
+    }
+
+    /// Path of the output file with the given fields, creating the
+    /// directories leading to it.
+    pub fn path(&self, fields: &NameFields) -> Result<PathBuf, Error> {
+        let mut name = String::new();
+        for (is_placeholder, s) in parse(&self.template)? {
+            if is_placeholder {
+                name.push_str(&fields.value(s)?);
+            } else {
+                name.push_str(s);
+            }
+        }
+        let path = self.dir.join(name);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok(path)
+    }
+}
+
+/// Creates the output file at `path`. An existing file is an error unless
+/// `overwrite` is set, so a rerun with the same name doesn't silently
+/// replace earlier output.
+pub fn create_file(path: &Path, overwrite: bool) -> Result<File, Error> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if overwrite {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    options.open(path).map_err(|e| match e.kind() {
+        ErrorKind::AlreadyExists => anyhow!(
+            "{} already exists, pass --overwrite to replace it",
+            path.display()
+        ),
+        _ => Error::new(e).context(format!("failed to create {}", path.display())),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> NameFields<'static> {
+        NameFields {
+            name: Some("neb"),
+            run: Some(12),
+            seed: Some(42),
+            width: 320,
+            height: 240,
+            iterations: 5000,
+            ext: "zip",
+        }
+    }
+
+    #[test]
+    fn placeholders_are_expanded() {
+        let output = OutputName::new(
+            None,
+            "{name}_{seed}_{run}_{width}x{height}_{iterations}.{ext}",
+        )
+        .unwrap();
+        assert_eq!(
+            output.path(&fields()).unwrap(),
+            PathBuf::from("neb_42_12_320x240_5000.zip")
+        );
+    }
+
+    #[test]
+    fn timestamp_is_milliseconds() {
+        let output = OutputName::new(None, "{timestamp}").unwrap();
+        let name = output.path(&fields()).unwrap();
+        let millis = name.to_str().unwrap().parse::<u128>().unwrap();
+        assert!(millis > 1_600_000_000_000);
+    }
+
+    #[test]
+    fn default_templates_are_valid() {
+        for template in [BUNDLE_TEMPLATE, IMAGE_TEMPLATE] {
+            OutputName::new(None, template).unwrap();
+        }
+        assert!(OutputName::new(None, BUNDLE_TEMPLATE)
+            .unwrap()
+            .uses("timestamp"));
+    }
+
+    #[test]
+    fn unknown_placeholder_is_rejected() {
+        let err = OutputName::new(None, "out_{frame}.zip").unwrap_err();
+        assert!(err.to_string().contains("{frame}"));
+    }
+
+    #[test]
+    fn unclosed_placeholder_is_rejected() {
+        assert!(OutputName::new(None, "out_{run.zip").is_err());
+        assert!(OutputName::new(None, "out_{}.zip").is_err());
+    }
+
+    #[test]
+    fn uses_only_matches_placeholders() {
+        let output = OutputName::new(None, "run_{seed}.zip").unwrap();
+        assert!(output.uses("seed"));
+        assert!(!output.uses("run"));
+    }
+
+    #[test]
+    fn missing_value_is_an_error() {
+        // merged bundles have no single seed or run
+        let merged = NameFields {
+            run: None,
+            seed: None,
+            ..fields()
+        };
+        let output = OutputName::new(None, "{name}_{run}.zip").unwrap();
+        let err = output.path(&merged).unwrap_err();
+        assert!(err.to_string().contains("{run}"));
+
+        let output = OutputName::new(None, "{name}.zip").unwrap();
+        assert_eq!(output.path(&merged).unwrap(), PathBuf::from("neb.zip"));
+    }
+
+    #[test]
+    fn directories_are_created() {
+        let dir = std::env::temp_dir().join(format!("buddhabrot-naming-{}", std::process::id()));
+        let output = OutputName::new(Some(&dir), "frames/{name}/{run}.{ext}").unwrap();
+        let path = output.path(&fields()).unwrap();
+        let created = dir.join("frames/neb").is_dir();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(path, dir.join("frames/neb/12.zip"));
+        assert!(created);
+    }
+
+    #[test]
+    fn existing_files_are_kept_unless_overwritten() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join(format!("buddhabrot-exists-{}", std::process::id()));
+        create_file(&path, false)
+            .unwrap()
+            .write_all(b"first")
+            .unwrap();
+        let err = create_file(&path, false).unwrap_err().to_string();
+        let kept = std::fs::read(&path).unwrap();
+        create_file(&path, true).unwrap().write_all(b"2").unwrap();
+        let replaced = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(err.contains(&path.display().to_string()) && err.contains("--overwrite"));
+        assert_eq!(kept, b"first");
+        assert_eq!(replaced, b"2");
+    }
+}
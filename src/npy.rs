@@ -0,0 +1,162 @@
+use anyhow::Error;
+use std::{
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::{bundle::BundleMeta, naming::create_file};
+
+/// Writes the counts as a NumPy `.npy` array to `path` and the render
+/// settings next to it as JSON.
+///
+/// The array has shape (height, width) for single channel bundles and
+/// (channels, height, width) otherwise. Counts are stored as `uint32`
+/// when they all fit and as `uint64` otherwise.
+pub fn write_npy(
+    path: &Path,
+    meta: &BundleMeta,
+    data: &[u64],
+    overwrite: bool,
+) -> Result<(), Error> {
+    let wide = data.iter().any(|c| *c > u32::MAX as u64);
+    let shape = match meta.num_channels() {
+        1 => format!("({}, {})", meta.height, meta.width),
+        n => format!("({n}, {}, {})", meta.height, meta.width),
+    };
+    let mut header = format!(
+        "{{'descr': '{}', 'fortran_order': False, 'shape': {shape}, }}",
+        if wide { "<u8" } else { "<u4" }
+    );
+    // the magic, version and header length take 10 bytes and the header is
+    // padded so that the data starts 64 byte aligned
+    let len = 10 + header.len() + 1;
+    header.push_str(&" ".repeat((64 - len % 64) % 64));
+    header.push('\n');
+
+    // neither file is left behind when the other one already exists
+    let json_path = path.with_extension("json");
+    let mut json = create_file(&json_path, overwrite)?;
+    let file = create_file(path, overwrite).inspect_err(|_| {
+        let _ = std::fs::remove_file(&json_path);
+    })?;
+    let mut w = BufWriter::new(file);
+    w.write_all(b"\x93NUMPY\x01\x00")?;
+    w.write_all(&(header.len() as u16).to_le_bytes())?;
+    w.write_all(header.as_bytes())?;
+    for c in data {
+        if wide {
+            w.write_all(&c.to_le_bytes())?;
+        } else {
+            w.write_all(&(*c as u32).to_le_bytes())?;
+        }
+    }
+    w.flush()?;
+
+    json.write_all(meta.to_json().as_bytes())?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::tests::{full_meta, temp_path};
+
+    /// Writes `data` and returns the `.npy` header, the array bytes and the
+    /// JSON sidecar.
+    fn write(name: &str, meta: &BundleMeta, data: &[u64]) -> (String, Vec<u8>, String) {
+        let path = temp_path(name);
+        write_npy(&path, meta, data, false).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let json = std::fs::read_to_string(path.with_extension("json")).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("json")).unwrap();
+
+        assert_eq!(&bytes[..8], b"\x93NUMPY\x01\x00");
+        let header_len = u16::from_le_bytes([bytes[8], bytes[9]]) as usize;
+        let header = String::from_utf8(bytes[10..10 + header_len].to_vec()).unwrap();
+        (header, bytes[10 + header_len..].to_vec(), json)
+    }
+
+    #[test]
+    fn data_is_64_byte_aligned() {
+        for meta in [BundleMeta::new(4, 3, 500), full_meta()] {
+            let len = (meta.width * meta.height * meta.num_channels()) as usize;
+            let (header, data, _) = write("aligned.npy", &meta, &vec![1; len]);
+            assert_eq!((10 + header.len()) % 64, 0, "{header:?}");
+            assert!(header.ends_with('\n'));
+            assert_eq!(data.len(), len * 4);
+        }
+    }
+
+    #[test]
+    fn single_channel_is_2d() {
+        let meta = BundleMeta::new(4, 3, 500);
+        let data = (0..12).collect::<Vec<u64>>();
+        let (header, bytes, _) = write("2d.npy", &meta, &data);
+        assert!(header.contains("'shape': (3, 4)"), "{header}");
+        assert!(header.contains("'descr': '<u4'"), "{header}");
+        assert!(header.contains("'fortran_order': False"), "{header}");
+        let read = bytes
+            .chunks(4)
+            .map(|c| u32::from_le_bytes(c.try_into().unwrap()) as u64)
+            .collect::<Vec<_>>();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn several_channels_are_3d() {
+        let meta = full_meta();
+        let (header, _, _) = write("3d.npy", &meta, &[0; 24]);
+        assert!(header.contains("'shape': (2, 3, 4)"), "{header}");
+    }
+
+    #[test]
+    fn large_counts_are_u8() {
+        let meta = BundleMeta::new(2, 1, 500);
+        let data = [1, u32::MAX as u64 + 1];
+        let (header, bytes, _) = write("wide.npy", &meta, &data);
+        assert!(header.contains("'descr': '<u8'"), "{header}");
+        let read = bytes
+            .chunks(8)
+            .map(|c| u64::from_le_bytes(c.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(read, data);
+    }
+
+    #[test]
+    fn settings_are_written_next_to_the_array() {
+        let meta = full_meta();
+        let (_, _, json) = write("settings.npy", &meta, &[0; 24]);
+        assert_eq!(json, meta.to_json());
+        for field in [
+            "\"width\": 4",
+            "\"height\": 3",
+            "\"max_iterations\": 500",
+            "\"channel_iterations\": [500, 50]",
+            "\"batch_trials\": 6400",
+            "\"seed\": 42",
+        ] {
+            assert!(json.contains(field), "{field} missing from {json}");
+        }
+    }
+
+    #[test]
+    fn existing_arrays_are_kept_unless_overwritten() {
+        let meta = BundleMeta::new(2, 1, 500);
+        let path = temp_path("exists.npy");
+        std::fs::write(&path, "old").unwrap();
+        let refused = write_npy(&path, &meta, &[1, 2], false);
+        let stray_json = path.with_extension("json").exists();
+        let kept = std::fs::read(&path).unwrap();
+        write_npy(&path, &meta, &[1, 2], true).unwrap();
+        let replaced = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(path.with_extension("json")).unwrap();
+
+        assert!(refused.is_err());
+        assert!(!stray_json);
+        assert_eq!(kept, b"old");
+        assert_ne!(replaced, b"old");
+    }
+}
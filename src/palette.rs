@@ -0,0 +1,256 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, ensure, Context, Error};
+
+/// Names of the built-in palettes.
+pub const PALETTE_NAMES: [&str; 5] = ["grey", "fire", "ice", "magma", "viridis"];
+
+/// Gradient mapping a tone mapped brightness in [0, 1] to a colour, made
+/// of colour stops that are interpolated linearly.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    stops: Vec<(f64, [f64; 3])>,
+}
+
+fn parse_color(s: &str) -> Result<[f64; 3], Error> {
+    let hex = s.trim_start_matches('#');
+    ensure!(
+        hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()),
+        "invalid colour {s}, expected #rrggbb"
+    );
+    let channel = |n: usize| u8::from_str_radix(&hex[n..n + 2], 16).unwrap() as f64 / 255.0;
+    Ok([channel(0), channel(2), channel(4)])
+}
+
+impl Palette {
+    /// Builds a palette from `(position, #rrggbb)` stops. Positions are in
+    /// [0, 1] and the first and last stop extend to the ends.
+    pub fn new(stops: &[(f64, &str)]) -> Result<Self, Error> {
+        ensure!(
+            !stops.is_empty(),
+            "a palette needs at least one colour stop"
+        );
+        let mut stops = stops
+            .iter()
+            .map(|(p, c)| {
+                ensure!(
+                    (0.0..=1.0).contains(p),
+                    "colour stop position {p} is outside 0 to 1"
+                );
+                Ok((*p, parse_color(c)?))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        stops.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Ok(Self { stops })
+    }
+
+    /// One of the `PALETTE_NAMES`.
+    pub fn named(name: &str) -> Option<Self> {
+        let stops: &[(f64, &str)] = match name {
+            "grey" => &[(0.0, "#000000"), (1.0, "#ffffff")],
+            "fire" => &[
+                (0.0, "#000000"),
+                (0.25, "#5a0a00"),
+                (0.5, "#c83200"),
+                (0.75, "#ffa000"),
+                (1.0, "#ffffff"),
+            ],
+            "ice" => &[
+                (0.0, "#000000"),
+                (0.35, "#0a285a"),
+                (0.7, "#50a0dc"),
+                (1.0, "#ffffff"),
+            ],
+            "magma" => &[
+                (0.0, "#000004"),
+                (0.25, "#3b0f70"),
+                (0.5, "#8c2981"),
+                (0.75, "#de4968"),
+                (0.9, "#fe9f6d"),
+                (1.0, "#fcfdbf"),
+            ],
+            "viridis" => &[
+                (0.0, "#440154"),
+                (0.25, "#3b528b"),
+                (0.5, "#21918c"),
+                (0.75, "#5ec962"),
+                (1.0, "#fde725"),
+            ],
+            _ => return None,
+        };
+        Some(Self::new(stops).unwrap())
+    }
+
+    /// Reads a gradient file with one `position #rrggbb` stop per line, e.g.
+    /// `0.5 #c83200`. Empty lines and lines starting with `#` are ignored.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read gradient {}", path.display()))?;
+        let mut stops = vec![];
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields = line
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|f| !f.is_empty())
+                .collect::<Vec<_>>();
+            let [position, color] = fields[..] else {
+                bail!("malformed colour stop in {}: {line}", path.display());
+            };
+            let position = position
+                .parse::<f64>()
+                .map_err(|_| anyhow!("invalid position in {}: {line}", path.display()))?;
+            stops.push((position, color));
+        }
+        Self::new(&stops).with_context(|| format!("invalid gradient {}", path.display()))
+    }
+
+    /// A built-in palette by name, otherwise the gradient file at `arg`.
+    pub fn from_arg(arg: &str) -> Result<Self, Error> {
+        match Self::named(arg) {
+            Some(palette) => Ok(palette),
+            None if Path::new(arg).exists() => Self::load(arg),
+            None => bail!(
+                "{arg} is neither a gradient file nor a palette, expected one of {}",
+                PALETTE_NAMES.join(", ")
+            ),
+        }
+    }
+
+    /// Colour of the brightness `x` in [0, 1].
+    pub fn sample(&self, x: f64) -> [f64; 3] {
+        let n = self.stops.partition_point(|(p, _)| *p <= x);
+        if n == 0 {
+            return self.stops[0].1;
+        }
+        if n == self.stops.len() {
+            return self.stops[n - 1].1;
+        }
+        let (p0, c0) = self.stops[n - 1];
+        let (p1, c1) = self.stops[n];
+        let t = (x - p0) / (p1 - p0);
+        [0, 1, 2].map(|i| c0[i] + (c1[i] - c0[i]) * t)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bundle::tests::temp_path;
+
+    #[test]
+    fn unsorted_stops_are_sorted() {
+        let sorted = Palette::new(&[(0.0, "#000000"), (0.5, "#ff0000"), (1.0, "#ffffff")]);
+        let unsorted = Palette::new(&[(1.0, "#ffffff"), (0.0, "#000000"), (0.5, "#ff0000")]);
+        assert_eq!(sorted.unwrap(), unsorted.unwrap());
+    }
+
+    #[test]
+    fn invalid_stops_are_rejected() {
+        assert!(Palette::new(&[]).is_err());
+        for color in ["#fff", "#ff00000", "#gg0000", "ff00", ""] {
+            assert!(Palette::new(&[(0.0, color)]).is_err(), "{color}");
+        }
+        assert!(Palette::new(&[(-0.1, "#000000")]).is_err());
+        assert!(Palette::new(&[(1.5, "#000000")]).is_err());
+        assert!(Palette::new(&[(f64::NAN, "#000000")]).is_err());
+    }
+
+    #[test]
+    fn colours_are_parsed_with_or_without_hash() {
+        let palette = Palette::new(&[(0.0, "#ff8000")]).unwrap();
+        assert_eq!(palette.sample(0.0), [1.0, 128.0 / 255.0, 0.0]);
+        assert_eq!(Palette::new(&[(0.0, "ff8000")]).unwrap(), palette);
+    }
+
+    #[test]
+    fn single_stop_is_flat() {
+        let palette = Palette::new(&[(0.3, "#336699")]).unwrap();
+        let color = [0.2, 0.4, 0.6];
+        for x in [0.0, 0.3, 0.7, 1.0] {
+            assert_eq!(palette.sample(x), color);
+        }
+    }
+
+    #[test]
+    fn sample_interpolates_between_stops() {
+        let palette =
+            Palette::new(&[(0.0, "#000000"), (0.5, "#ff0000"), (1.0, "#ffffff")]).unwrap();
+        assert_eq!(palette.sample(0.0), [0.0, 0.0, 0.0]);
+        assert_eq!(palette.sample(0.25), [0.5, 0.0, 0.0]);
+        assert_eq!(palette.sample(0.5), [1.0, 0.0, 0.0]);
+        assert_eq!(palette.sample(0.75), [1.0, 0.5, 0.5]);
+        assert_eq!(palette.sample(1.0), [1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn sample_extends_the_end_stops() {
+        let palette = Palette::new(&[(0.2, "#000000"), (0.8, "#ffffff")]).unwrap();
+        assert_eq!(palette.sample(0.0), [0.0, 0.0, 0.0]);
+        assert_eq!(palette.sample(-1.0), [0.0, 0.0, 0.0]);
+        assert!(palette.sample(0.5).iter().all(|c| (c - 0.5).abs() < 1e-12));
+        assert_eq!(palette.sample(1.0), [1.0, 1.0, 1.0]);
+        assert_eq!(palette.sample(2.0), [1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn named_palettes_span_zero_to_one() {
+        for name in PALETTE_NAMES {
+            let palette = Palette::named(name).unwrap();
+            assert_eq!(palette.stops.first().unwrap().0, 0.0, "{name}");
+            assert_eq!(palette.stops.last().unwrap().0, 1.0, "{name}");
+        }
+        assert_eq!(Palette::named("plasma"), None);
+    }
+
+    #[test]
+    fn gradient_files_are_loaded() {
+        let path = temp_path("gradient.txt");
+        std::fs::write(
+            &path,
+            "# position colour\n\n1.0 #ffffff\n 0.0, #000000 \n0.5\t#ff0000\n",
+        )
+        .unwrap();
+        let loaded = Palette::load(&path);
+        let from_arg = Palette::from_arg(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let expected = Palette::new(&[(0.0, "#000000"), (0.5, "#ff0000"), (1.0, "#ffffff")]);
+        assert_eq!(loaded.unwrap(), *expected.as_ref().unwrap());
+        assert_eq!(from_arg.unwrap(), expected.unwrap());
+    }
+
+    #[test]
+    fn malformed_gradient_files_are_rejected() {
+        for (name, text) in [
+            ("gradient-fields.txt", "0.0 #000000 extra\n"),
+            ("gradient-position.txt", "zero #000000\n"),
+            ("gradient-colour.txt", "0.0 #00000\n"),
+            ("gradient-empty.txt", "# no stops\n"),
+        ] {
+            let path = temp_path(name);
+            std::fs::write(&path, text).unwrap();
+            let err = Palette::load(&path).unwrap_err();
+            std::fs::remove_file(&path).unwrap();
+            assert!(format!("{err:#}").contains(name), "{err:#}");
+        }
+        assert!(Palette::load(temp_path("gradient-missing.txt")).is_err());
+    }
+
+    #[test]
+    fn from_arg_prefers_palette_names() {
+        assert_eq!(
+            Palette::from_arg("fire").unwrap(),
+            Palette::named("fire").unwrap()
+        );
+        let err = Palette::from_arg("no-such-palette")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("no-such-palette") && err.contains("viridis"),
+            "{err}"
+        );
+    }
+}
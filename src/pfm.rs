@@ -0,0 +1,44 @@
+use anyhow::Error;
+use std::{
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use crate::naming::create_file;
+
+/// Writes a Portable Float Map to `path`, greyscale from the first value of
+/// each pixel if `grey` is set and RGB otherwise.
+///
+/// The values are written as they are, so they can hold tone mapped levels
+/// or the raw counts for grading elsewhere. Counts above 2^24 lose
+/// precision as 32-bit floats.
+pub fn write_pfm(
+    path: &Path,
+    width: u32,
+    height: u32,
+    pixels: &[[f64; 3]],
+    grey: bool,
+    overwrite: bool,
+) -> Result<(), Error> {
+    let mut w = BufWriter::new(create_file(path, overwrite)?);
+
+    // a negative scale marks little endian samples
+    write!(
+        w,
+        "{}\n{width} {height}\n-1.0\n",
+        if grey { "Pf" } else { "PF" }
+    )?;
+
+    // rows are stored bottom to top
+    for row in pixels.chunks(width as usize).rev() {
+        for pixel in row {
+            let values = if grey { &pixel[..1] } else { &pixel[..] };
+            for v in values {
+                w.write_all(&(*v as f32).to_le_bytes())?;
+            }
+        }
+    }
+    w.flush()?;
+
+    Ok(())
+}
@@ -1,25 +1,49 @@
-use anyhow::Error;
+use anyhow::{bail, ensure, Error};
 use std::{
     fs::File,
     io::{BufWriter, Read},
     path::Path,
-    time::SystemTime,
 };
-use zip::ZipArchive;
+use zip::{result::ZipError, ZipArchive};
 
-pub fn read_bundle_data<P>(bpath: P) -> Result<(u32, u32, u32, Vec<u32>), Error>
+use crate::{
+    bundle::{BundleMeta, FORMAT_VERSION, MANIFEST_NAME},
+    naming::create_file,
+    palette::Palette,
+    tone::ToneMap,
+};
+
+/// Reads a bundle of any format version up to `FORMAT_VERSION`.
+pub fn read_bundle_data<P>(bpath: P) -> Result<(BundleMeta, Vec<u64>), Error>
 where
     P: AsRef<Path>,
 {
-    let bfile = File::open(bpath)?;
+    let path = bpath.as_ref();
+    let bfile = File::open(path)?;
     let mut zip = ZipArchive::new(&bfile)?;
 
+    let manifest = match zip.by_name(MANIFEST_NAME) {
+        Ok(mut f) => {
+            let mut s = String::new();
+            f.read_to_string(&mut s)?;
+            Some(s)
+        }
+        Err(ZipError::FileNotFound) => None,
+        Err(e) => return Err(e.into()),
+    };
+
     let mut datafile = zip.by_name("data.bin")?;
     println!("Filename: {}", datafile.name());
 
     let mut buf = [0; 1];
     datafile.read_exact(&mut buf)?;
-    assert!(buf[0] == 0x01);
+    let version = buf[0];
+    if version == 0 || version > FORMAT_VERSION {
+        bail!(
+            "{} has bundle format {version}, only 1 to {FORMAT_VERSION} are supported",
+            path.display()
+        );
+    }
 
     let mut buf = [0; 4];
     datafile.read_exact(&mut buf)?;
@@ -31,43 +55,255 @@ where
     datafile.read_exact(&mut buf)?;
     let height = u32::from_le_bytes(buf);
 
-    let mut buf = Vec::with_capacity(width as usize * height as usize * std::mem::size_of::<u32>());
+    let mut buf = Vec::with_capacity(width as usize * height as usize * std::mem::size_of::<u64>());
     datafile.read_to_end(&mut buf)?;
 
-    let data = bytemuck::cast_slice::<u8, u32>(&buf)
-        .into_iter()
-        .cloned()
-        .collect::<Vec<u32>>();
+    // counts were 32 bits wide before version 3
+    let data = if version >= 3 {
+        buf.chunks_exact(std::mem::size_of::<u64>())
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+            .collect::<Vec<u64>>()
+    } else {
+        buf.chunks_exact(std::mem::size_of::<u32>())
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()) as u64)
+            .collect::<Vec<u64>>()
+    };
+
+    let mut meta = BundleMeta::new(width, height, iterations);
+    match manifest {
+        Some(manifest) => meta.apply_manifest(&manifest)?,
+        None if version >= 2 => bail!("{} has no {MANIFEST_NAME}", path.display()),
+        None => {}
+    }
+
+    ensure!(
+        data.len() == width as usize * height as usize * meta.num_channels() as usize,
+        "{} holds {} counts, expected {}x{}x{}",
+        path.display(),
+        data.len(),
+        width,
+        height,
+        meta.num_channels()
+    );
+
+    Ok((meta, data))
+}
 
-    assert!(data.len() as u32 == width * height);
+/// Tone maps the counts into RGB levels in [0, 1]. A single channel goes
+/// through `palette`, or is grey without one, otherwise channels 0, 1 and
+/// 2 become red, green and blue, each tone mapped on its own.
+pub fn rgb_levels(
+    width: u32,
+    height: u32,
+    channels: u32,
+    input_data: &[u64],
+    tone: &ToneMap,
+    palette: Option<&Palette>,
+) -> Result<Vec<[f64; 3]>, Error> {
+    let channel_size = width as usize * height as usize;
+    if channels == 1 {
+        let levels = tone.apply(&input_data[..channel_size]);
+        return Ok(match palette {
+            Some(palette) => levels.iter().map(|x| palette.sample(*x)).collect(),
+            None => levels.iter().map(|x| [*x; 3]).collect(),
+        });
+    }
+
+    ensure!(
+        palette.is_none(),
+        "palettes only apply to single channel bundles, these have {channels}"
+    );
+    if channels > 3 {
+        log::warn!("only the first 3 of {channels} channels are written");
+    }
+    let levels = input_data
+        .chunks(channel_size)
+        .take(3)
+        .map(|p| tone.apply(p))
+        .collect::<Vec<_>>();
+    Ok((0..channel_size)
+        .map(|n| [0, 1, 2].map(|ch| levels.get(ch).map(|p| p[n]).unwrap_or(0.0)))
+        .collect())
+}
+
+/// Counts of one colour channel of a composed image.
+pub struct ChannelSource<'a> {
+    pub counts: &'a [u64],
+    pub tone: ToneMap,
+    pub weight: f64,
+}
+
+/// Tone maps each source into its colour channel of an image of `pixels`
+/// pixels, scaled by its weight and clipped to 1. Channels without a
+/// source stay black.
+pub fn compose_levels(pixels: usize, sources: [Option<ChannelSource>; 3]) -> Vec<[f64; 3]> {
+    let levels = sources.map(|source| {
+        source.map(|s| {
+            s.tone
+                .apply(&s.counts[..pixels])
+                .into_iter()
+                .map(|x| (x * s.weight).min(1.0))
+                .collect::<Vec<_>>()
+        })
+    });
+    (0..pixels)
+        .map(|n| [0, 1, 2].map(|ch| levels[ch].as_ref().map(|p| p[n]).unwrap_or(0.0)))
+        .collect()
+}
 
-    Ok((width, height, iterations, data))
+/// Sample layout of a written PNG.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PngFormat {
+    /// 16-bit RGB
+    Rgb16,
+    /// 16-bit greyscale of the red levels, for grey images
+    Grey16,
+    /// 8-bit RGB, ordered dithering keeps smooth gradients from banding
+    Rgb8,
 }
 
-pub fn write_png(width: u32, height: u32, input_data: &Vec<u64>) -> Result<(), Error> {
-    let since_epoch = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?;
-    let filename = format!("{}.png", since_epoch.as_millis(),);
+/// 4x4 Bayer matrix of the ordered dithering.
+const BAYER: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
 
-    let path = Path::new(&filename);
-    let file = File::create(path).unwrap();
-    let ref mut w = BufWriter::new(file);
+/// Writes RGB levels in [0, 1] as a PNG to `path`.
+pub fn write_png(
+    path: &Path,
+    width: u32,
+    height: u32,
+    pixels: &[[f64; 3]],
+    format: PngFormat,
+    overwrite: bool,
+) -> Result<(), Error> {
+    let file = create_file(path, overwrite)?;
+    let w = &mut BufWriter::new(file);
     let mut encoder = png::Encoder::new(w, width, height);
-    encoder.set_color(png::ColorType::Rgb);
-    encoder.set_depth(png::BitDepth::Sixteen);
+    let (color, depth) = match format {
+        PngFormat::Rgb16 => (png::ColorType::Rgb, png::BitDepth::Sixteen),
+        PngFormat::Grey16 => (png::ColorType::Grayscale, png::BitDepth::Sixteen),
+        PngFormat::Rgb8 => (png::ColorType::Rgb, png::BitDepth::Eight),
+    };
+    encoder.set_color(color);
+    encoder.set_depth(depth);
     let mut writer = encoder.write_header().unwrap();
 
-    let max = input_data.iter().max().unwrap().clone();
-    let mut data = Vec::with_capacity(input_data.len() * 3 * std::mem::size_of::<u16>());
-    for d in input_data.iter() {
-        let v = d * 0xffff / max;
-        assert!(v <= 0xffff);
-        let bytes = (v as u16).to_be_bytes();
-        data.extend_from_slice(&bytes);
-        data.extend_from_slice(&bytes);
-        data.extend_from_slice(&bytes);
+    let mut data = Vec::with_capacity(pixels.len() * 3 * std::mem::size_of::<u16>());
+    for (n, pixel) in pixels.iter().enumerate() {
+        match format {
+            PngFormat::Rgb16 => {
+                for v in pixel {
+                    data.extend_from_slice(&((v * 65535.0).round() as u16).to_be_bytes());
+                }
+            }
+            PngFormat::Grey16 => {
+                data.extend_from_slice(&((pixel[0] * 65535.0).round() as u16).to_be_bytes());
+            }
+            PngFormat::Rgb8 => {
+                let (x, y) = (n % width as usize, n / width as usize);
+                let threshold = (BAYER[y % 4][x % 4] as f64 + 0.5) / 16.0;
+                for v in pixel {
+                    data.push((v * 255.0 + threshold).floor().min(255.0) as u8);
+                }
+            }
+        }
     }
 
     writer.write_image_data(&data).unwrap();
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::{
+        bundle::tests::{full_meta, temp_path},
+        fractal::dump_to_file,
+    };
+
+    /// Zip with a `data.bin` of the given version holding u32 counts, and a
+    /// manifest if one is given.
+    fn write_old_bundle(
+        path: &Path,
+        version: u8,
+        meta: &BundleMeta,
+        counts: &[u32],
+        manifest: Option<&str>,
+    ) {
+        let mut zip = zip::ZipWriter::new(File::create(path).unwrap());
+        let options = zip::write::FileOptions::default();
+        zip.start_file("data.bin", options).unwrap();
+        zip.write_all(&[version]).unwrap();
+        zip.write_all(&meta.max_iters.to_le_bytes()).unwrap();
+        zip.write_all(&meta.width.to_le_bytes()).unwrap();
+        zip.write_all(&meta.height.to_le_bytes()).unwrap();
+        zip.write_all(bytemuck::cast_slice(counts)).unwrap();
+        if let Some(manifest) = manifest {
+            zip.start_file(MANIFEST_NAME, options).unwrap();
+            zip.write_all(manifest.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn current_bundle_round_trip() {
+        let meta = full_meta();
+        let data = (0..24u64).map(|n| n << 33 | n).collect::<Vec<_>>();
+        let path = temp_path("v3.zip");
+        dump_to_file(&meta, &data, &path, false).unwrap();
+        let read = read_bundle_data(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(read.unwrap(), (meta, data));
+    }
+
+    #[test]
+    fn version_1_bundle_without_manifest() {
+        let meta = BundleMeta::new(4, 3, 200);
+        let counts = (0..12u32)
+            .map(|n| n * 1000 + u32::MAX / 2)
+            .collect::<Vec<_>>();
+        let path = temp_path("v1.zip");
+        write_old_bundle(&path, 1, &meta, &counts, None);
+        let read = read_bundle_data(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let (read_meta, data) = read.unwrap();
+        assert_eq!(read_meta, meta);
+        assert_eq!(data, counts.iter().map(|c| *c as u64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn version_2_bundle_with_manifest() {
+        let meta = full_meta();
+        let counts = (0..24u32).collect::<Vec<_>>();
+        let path = temp_path("v2.zip");
+        write_old_bundle(&path, 2, &meta, &counts, Some(&meta.to_manifest()));
+        let read = read_bundle_data(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let (read_meta, data) = read.unwrap();
+        assert_eq!(read_meta, meta);
+        assert_eq!(data, (0..24u64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn version_2_bundle_needs_manifest() {
+        let meta = BundleMeta::new(4, 3, 200);
+        let path = temp_path("v2-no-manifest.zip");
+        write_old_bundle(&path, 2, &meta, &[0; 12], None);
+        let read = read_bundle_data(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(read.is_err());
+    }
+
+    #[test]
+    fn wrong_count_length_is_rejected() {
+        let meta = full_meta();
+        let path = temp_path("short.zip");
+        write_old_bundle(&path, 2, &meta, &[0; 12], Some(&meta.to_manifest()));
+        let read = read_bundle_data(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert!(read.is_err());
+    }
+}
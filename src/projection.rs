@@ -0,0 +1,167 @@
+use std::{fmt, str::FromStr};
+
+use anyhow::{anyhow, Error};
+
+/// Names of the axes of the space orbits live in, `(z.re, z.im, c.re, c.im)`.
+pub const AXES: [&str; 4] = ["zr", "zi", "cr", "ci"];
+
+/// Rows of the 2x4 matrix projecting `(z.re, z.im, c.re, c.im)` onto the
+/// screen's x and y, which the zoom window is then taken from.
+pub type Projection = [[f64; 4]; 2];
+
+/// The usual Buddhabrot projection onto the `z` plane.
+pub const Z_PLANE: Projection = [[1.0, 0.0, 0.0, 0.0], [0.0, 1.0, 0.0, 0.0]];
+
+/// Rotation in the plane of two axes, written as `zr-cr=30` for 30 degrees
+/// in the plane of `z.re` and `c.re`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PlaneRotation {
+    pub axes: (usize, usize),
+    pub degrees: f64,
+}
+
+impl PlaneRotation {
+    /// The same rotation `n` times over, used to step animations.
+    pub fn times(self, n: f64) -> Self {
+        Self {
+            degrees: self.degrees * n,
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for PlaneRotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}={}",
+            AXES[self.axes.0], AXES[self.axes.1], self.degrees
+        )
+    }
+}
+
+impl FromStr for PlaneRotation {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        let invalid = || anyhow!("invalid rotation {s}, expected e.g. zr-cr=30");
+        let (plane, degrees) = s.split_once('=').ok_or_else(invalid)?;
+        let (a, b) = plane.split_once('-').ok_or_else(invalid)?;
+        let axis = |name: &str| {
+            AXES.iter()
+                .position(|axis| *axis == name.trim())
+                .ok_or_else(|| anyhow!("unknown axis {name}, expected one of {AXES:?}"))
+        };
+        let axes = (axis(a)?, axis(b)?);
+        if axes.0 == axes.1 {
+            return Err(invalid());
+        }
+        let degrees = degrees.trim().parse().map_err(|_| invalid())?;
+        Ok(Self { axes, degrees })
+    }
+}
+
+/// Applies `rotations` in order to the 4D space before it is projected.
+pub fn rotate(projection: Projection, rotations: &[PlaneRotation]) -> Projection {
+    let mut m = projection;
+    for rotation in rotations {
+        let (i, j) = rotation.axes;
+        let (sin, cos) = rotation.degrees.to_radians().sin_cos();
+        for row in m.iter_mut() {
+            let (a, b) = (row[i], row[j]);
+            row[i] = a * cos + b * sin;
+            row[j] = b * cos - a * sin;
+        }
+    }
+    m
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rotation(s: &str) -> PlaneRotation {
+        s.parse().unwrap()
+    }
+
+    fn assert_orthonormal(m: Projection) {
+        let dot = |a: [f64; 4], b: [f64; 4]| a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>();
+        assert!((dot(m[0], m[0]) - 1.0).abs() < 1e-12, "{m:?}");
+        assert!((dot(m[1], m[1]) - 1.0).abs() < 1e-12, "{m:?}");
+        assert!(dot(m[0], m[1]).abs() < 1e-12, "{m:?}");
+    }
+
+    #[test]
+    fn rotations_parse() {
+        assert_eq!(
+            rotation("zr-cr=30"),
+            PlaneRotation {
+                axes: (0, 2),
+                degrees: 30.0
+            }
+        );
+        assert_eq!(
+            rotation(" zi - ci = -45.5 "),
+            PlaneRotation {
+                axes: (1, 3),
+                degrees: -45.5
+            }
+        );
+        let r = rotation("ci-zr=12.25");
+        assert_eq!(r.to_string().parse::<PlaneRotation>().unwrap(), r);
+    }
+
+    #[test]
+    fn invalid_rotations_are_rejected() {
+        for s in [
+            "zr-zr=30",
+            "zx-cr=30",
+            "zr-cr",
+            "zr=30",
+            "zr-cr=",
+            "zr-cr=fast",
+        ] {
+            assert!(s.parse::<PlaneRotation>().is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn rotations_keep_the_projection_orthonormal() {
+        let rotations = [
+            "zr-cr=30",
+            "zi-ci=-75",
+            "zr-zi=12.5",
+            "cr-ci=200",
+            "zi-cr=1e-3",
+        ]
+        .map(rotation);
+        let mut m = Z_PLANE;
+        for r in rotations {
+            m = rotate(m, &[r]);
+            assert_orthonormal(m);
+        }
+        assert_orthonormal(rotate(Z_PLANE, &rotations.map(|r| r.times(37.0))));
+    }
+
+    fn assert_close(m: Projection, expected: Projection) {
+        for (row, expected) in m.iter().zip(expected) {
+            for (v, e) in row.iter().zip(expected) {
+                assert!((v - e).abs() < 1e-12, "{m:?} != {expected:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn rotations_compose() {
+        let r = rotation("zr-cr=30");
+        assert_close(rotate(Z_PLANE, &[r, r.times(-1.0)]), Z_PLANE);
+        assert_close(
+            rotate(Z_PLANE, &[r, r, r]),
+            rotate(Z_PLANE, &[r.times(3.0)]),
+        );
+
+        // a quarter turn swaps z.re for c.re and leaves z.im alone
+        let quarter = rotate(Z_PLANE, &[r.times(3.0)]);
+        assert_close(quarter, [[0.0, 0.0, -1.0, 0.0], Z_PLANE[1]]);
+    }
+}
@@ -0,0 +1,71 @@
+// Orbit coordinates as unevaluated sums of two f32 (double-float), about
+// 48 bits of mantissa for adapters without SHADER_F64. The algorithms are
+// from Dekker and from Hida, Li and Bailey's QD library.
+alias real = vec2f;
+
+fn quick_two_sum(a: f32, b: f32) -> vec2f {
+    let s = a + b;
+    return vec2f(s, b - (s - a));
+}
+
+fn two_sum(a: f32, b: f32) -> vec2f {
+    let s = a + b;
+    let bb = s - a;
+    return vec2f(s, (a - (s - bb)) + (b - bb));
+}
+
+fn two_prod(a: f32, b: f32) -> vec2f {
+    let p = a * b;
+    return vec2f(p, fma(a, b, -p));
+}
+
+fn r(x: f32) -> real {
+    return vec2f(x, 0.0);
+}
+
+// value of hi + lo, such as the parts of a host f64
+fn r2(hi: f32, lo: f32) -> real {
+    return two_sum(hi, lo);
+}
+
+fn r_parts(a: real) -> vec2f {
+    return a;
+}
+
+fn r_f32(a: real) -> f32 {
+    return a.x;
+}
+
+fn r_add(a: real, b: real) -> real {
+    var s = two_sum(a.x, b.x);
+    let t = two_sum(a.y, b.y);
+    s.y += t.x;
+    s = quick_two_sum(s.x, s.y);
+    s.y += t.y;
+    return quick_two_sum(s.x, s.y);
+}
+
+fn r_sub(a: real, b: real) -> real {
+    return r_add(a, -b);
+}
+
+fn r_mul(a: real, b: real) -> real {
+    var p = two_prod(a.x, b.x);
+    p.y += a.x * b.y + a.y * b.x;
+    return quick_two_sum(p.x, p.y);
+}
+
+fn r_lt(a: real, b: real) -> bool {
+    return a.x < b.x || (a.x == b.x && a.y < b.y);
+}
+
+fn r_le(a: real, b: real) -> bool {
+    return a.x < b.x || (a.x == b.x && a.y <= b.y);
+}
+
+fn r_abs(a: real) -> real {
+    if r_lt(a, vec2f(0.0)) {
+        return -a;
+    }
+    return a;
+}
@@ -0,0 +1,43 @@
+// Orbit coordinates in single precision.
+alias real = f32;
+
+fn r(x: f32) -> real {
+    return x;
+}
+
+// value of hi + lo, such as the parts of a host f64
+fn r2(hi: f32, lo: f32) -> real {
+    return hi;
+}
+
+fn r_parts(a: real) -> vec2f {
+    return vec2f(a, 0.0);
+}
+
+fn r_f32(a: real) -> f32 {
+    return a;
+}
+
+fn r_add(a: real, b: real) -> real {
+    return a + b;
+}
+
+fn r_sub(a: real, b: real) -> real {
+    return a - b;
+}
+
+fn r_mul(a: real, b: real) -> real {
+    return a * b;
+}
+
+fn r_lt(a: real, b: real) -> bool {
+    return a < b;
+}
+
+fn r_le(a: real, b: real) -> bool {
+    return a <= b;
+}
+
+fn r_abs(a: real) -> real {
+    return abs(a);
+}
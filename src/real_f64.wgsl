@@ -0,0 +1,44 @@
+// Orbit coordinates in double precision, needs SHADER_F64.
+alias real = f64;
+
+fn r(x: f32) -> real {
+    return f64(x);
+}
+
+// value of hi + lo, such as the parts of a host f64
+fn r2(hi: f32, lo: f32) -> real {
+    return f64(hi) + f64(lo);
+}
+
+fn r_parts(a: real) -> vec2f {
+    let hi = f32(a);
+    return vec2f(hi, f32(a - f64(hi)));
+}
+
+fn r_f32(a: real) -> f32 {
+    return f32(a);
+}
+
+fn r_add(a: real, b: real) -> real {
+    return a + b;
+}
+
+fn r_sub(a: real, b: real) -> real {
+    return a - b;
+}
+
+fn r_mul(a: real, b: real) -> real {
+    return a * b;
+}
+
+fn r_lt(a: real, b: real) -> bool {
+    return a < b;
+}
+
+fn r_le(a: real, b: real) -> bool {
+    return a <= b;
+}
+
+fn r_abs(a: real) -> real {
+    return abs(a);
+}
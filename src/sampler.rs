@@ -0,0 +1,218 @@
+use anyhow::{anyhow, Error};
+use num::Complex;
+
+use crate::{formula::Formula, projection::Projection};
+
+/// Which orbits get binned into the frame.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RenderMode {
+    /// Orbits of points that escape within the iteration limit
+    #[default]
+    Buddhabrot,
+    /// Orbits of points that stay bounded for the whole iteration limit
+    Anti,
+}
+
+impl RenderMode {
+    /// Value passed to the kernel in `GPUVars::mode`.
+    pub fn as_u32(self) -> u32 {
+        match self {
+            RenderMode::Buddhabrot => 0,
+            RenderMode::Anti => 1,
+        }
+    }
+}
+
+/// How the points fed to the kernel are chosen.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SamplingMethod {
+    /// Every trial is an independent uniform sample of the full window
+    #[default]
+    Uniform,
+    /// Each trial advances a Metropolis-Hastings chain that favours samples
+    /// whose orbits hit the zoom window, weighting them to stay unbiased
+    Metropolis,
+}
+
+impl SamplingMethod {
+    /// Value passed to the kernel in `GPUVars::sampling`.
+    pub fn as_u32(self) -> u32 {
+        match self {
+            SamplingMethod::Uniform => 0,
+            SamplingMethod::Metropolis => 1,
+        }
+    }
+}
+
+/// Floating point type the orbits are iterated in.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Precision {
+    /// 32-bit floats, fast but blocky below zoom widths of about 1e-5
+    #[default]
+    Single,
+    /// 64-bit floats, emulated with pairs of 32-bit floats on GPUs
+    /// without native f64 support
+    Double,
+}
+
+/// Test deciding when an orbit has escaped, given the escape radius R.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EscapeTest {
+    /// |z| > R
+    #[default]
+    Circle,
+    /// |re z| > R
+    Real,
+    /// |im z| > R
+    Imag,
+    /// |re z| + |im z| > R
+    Manhattan,
+    /// max(|re z|, |im z|) > R
+    Square,
+}
+
+impl EscapeTest {
+    /// Whether no point with |z| <= 2 passes the test at `radius`. The
+    /// interior check relies on this, since it classes samples as bounded
+    /// by orbits that stay within |z| <= 2.
+    pub fn contains_disc(self, radius: f64) -> bool {
+        match self {
+            EscapeTest::Manhattan => radius >= 2.0 * std::f64::consts::SQRT_2,
+            _ => radius >= 2.0,
+        }
+    }
+}
+
+/// Escape radius of renders that don't set one, |z|^2 > 8.
+pub const DEFAULT_ESCAPE_RADIUS: f64 = 2.0 * std::f64::consts::SQRT_2;
+
+/// Most count channels a single render can accumulate.
+pub const MAX_CHANNELS: usize = 4;
+
+/// Everything a backend needs to know about the render besides the samples.
+///
+/// `channel_iters` holds one iteration limit per count channel and
+/// `max_iters` is the highest of them. Orbits shorter than `min_iters` are
+/// not counted.
+#[derive(Clone, Debug)]
+pub struct RenderParams {
+    pub width: u32,
+    pub height: u32,
+    pub max_iters: u32,
+    pub channel_iters: Vec<u32>,
+    pub min_iters: u32,
+    pub mode: RenderMode,
+    pub sampling: SamplingMethod,
+    pub formula: Formula,
+    /// When an orbit counts as escaped, which also ends the orbit
+    pub escape_test: EscapeTest,
+    pub escape_radius: f64,
+    /// Classify samples in the main cardioid and period-2 bulb analytically,
+    /// only valid for the Mandelbrot set
+    pub interior_check: bool,
+    pub precision: Precision,
+    pub lower_left: Complex<f64>,
+    pub upper_right: Complex<f64>,
+    pub zoom_lower_left: Complex<f64>,
+    pub zoom_upper_right: Complex<f64>,
+    /// Fixed c of a Julia set render, whose samples are the starting points
+    /// of the orbits instead of c
+    pub julia: Option<Complex<f64>>,
+    /// Projection of the orbits' `(z.re, z.im, c.re, c.im)` onto the screen
+    pub projection: Projection,
+}
+
+impl RenderParams {
+    pub fn num_channels(&self) -> u32 {
+        self.channel_iters.len() as u32
+    }
+
+    /// Number of counts in a frame covering every channel.
+    pub fn frame_len(&self) -> usize {
+        self.width as usize * self.height as usize * self.num_channels() as usize
+    }
+
+    /// Value the escape test compares against in f32, the squared radius
+    /// for the circle and the radius otherwise.
+    pub fn escape_limit(&self) -> f32 {
+        match self.escape_test {
+            EscapeTest::Circle => (self.escape_radius * self.escape_radius) as f32,
+            _ => self.escape_radius as f32,
+        }
+    }
+}
+
+/// Counters reported by a backend for one `accumulate` call.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SampleStats {
+    /// Samples found in the main cardioid by the interior check
+    pub cardioid: u64,
+    /// Samples found in the period-2 bulb by the interior check
+    pub bulb: u64,
+}
+
+impl std::ops::AddAssign for SampleStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.cardioid += rhs.cardioid;
+        self.bulb += rhs.bulb;
+    }
+}
+
+/// One batch of trials for a backend to run.
+///
+/// Trial `t` draws its random numbers from `pcg4d(t, k, seed)`, see
+/// `random4` in `shader.wgsl`, so a batch is fully described by its seed
+/// and size and every backend generates the same samples for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SampleBatch {
+    pub seed: u64,
+    pub trials: u32,
+}
+
+/// Adds `counts` into `frame`, failing instead of wrapping when a count no
+/// longer fits.
+pub fn add_counts(frame: &mut [u64], counts: impl Iterator<Item = u64>) -> Result<(), Error> {
+    for (n, (v, c)) in frame.iter_mut().zip(counts).enumerate() {
+        *v = v
+            .checked_add(c)
+            .ok_or_else(|| anyhow!("count {n} overflowed 64 bits"))?;
+    }
+    Ok(())
+}
+
+/// A backend that runs Buddhabrot trials and bins the orbits into a frame.
+///
+/// Implementations add the counts for the trials of `batch` into `frame`,
+/// which holds `width * height` counts for each channel, one channel after
+/// the other. Backends keep any sampler state, such as Metropolis-Hastings
+/// chains, between calls.
+pub trait Sampler {
+    fn accumulate(
+        &mut self,
+        params: &RenderParams,
+        batch: &SampleBatch,
+        frame: &mut [u64],
+    ) -> Result<SampleStats, Error>;
+
+    /// Drops the state kept between calls so the next batch starts fresh.
+    fn reset(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_counts_sums() {
+        let mut frame = vec![1, u64::MAX - 2, 0];
+        add_counts(&mut frame, [2, 2, 5].into_iter()).unwrap();
+        assert_eq!(frame, vec![3, u64::MAX, 5]);
+    }
+
+    #[test]
+    fn add_counts_fails_instead_of_wrapping() {
+        let mut frame = vec![0, u64::MAX - 1];
+        let err = add_counts(&mut frame, [1, 2].into_iter()).unwrap_err();
+        assert!(err.to_string().contains("count 1 overflowed"));
+    }
+}
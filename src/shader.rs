@@ -0,0 +1,109 @@
+//! Assembles the WGSL source of the kernel.
+
+use crate::{
+    formula::Formula,
+    sampler::{EscapeTest, RenderParams},
+};
+
+/// Number type the kernel iterates orbits in, picked from the requested
+/// precision and what the device supports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShaderReal {
+    F32,
+    F64,
+    /// Unevaluated sum of two f32, about 48 bits of mantissa
+    DoubleFloat,
+}
+
+/// `orbit_step` of `formula`, one step of the orbit written with the
+/// `real` operations of the preludes.
+fn orbit_step(formula: Formula) -> String {
+    let body = match formula {
+        Formula::Mandelbrot => "
+    let re = r_add(r_sub(r_mul(z.re, z.re), r_mul(z.im, z.im)), c.re);
+    let im = r_add(r_mul(r_add(z.re, z.re), z.im), c.im);
+    return Complex(re, im);"
+            .to_string(),
+        Formula::Multibrot(d) => format!(
+            "
+    var w = z;
+    for (var k = 1u; k < {d}u; k++) {{
+        let re = r_sub(r_mul(w.re, z.re), r_mul(w.im, z.im));
+        let im = r_add(r_mul(w.re, z.im), r_mul(w.im, z.re));
+        w = Complex(re, im);
+    }}
+    return Complex(r_add(w.re, c.re), r_add(w.im, c.im));"
+        ),
+        Formula::MultibrotReal(d) => format!(
+            "
+    let power: f32 = {:?};
+    let x = r_f32(z.re);
+    let y = r_f32(z.im);
+    // atan2 and pow are undefined at 0
+    if (x == 0.0 && y == 0.0) {{
+        return c;
+    }}
+    let m = pow(x * x + y * y, power * 0.5);
+    let a = atan2(y, x) * power;
+    return Complex(r_add(r(m * cos(a)), c.re), r_add(r(m * sin(a)), c.im));",
+            d as f32
+        ),
+        Formula::BurningShip => "
+    let re = r_add(r_sub(r_mul(z.re, z.re), r_mul(z.im, z.im)), c.re);
+    let im = r_add(r_mul(r_abs(r_add(z.re, z.re)), r_abs(z.im)), c.im);
+    return Complex(re, im);"
+            .to_string(),
+        Formula::Tricorn => "
+    let re = r_add(r_sub(r_mul(z.re, z.re), r_mul(z.im, z.im)), c.re);
+    let im = r_sub(c.im, r_mul(r_add(z.re, z.re), z.im));
+    return Complex(re, im);"
+            .to_string(),
+        Formula::Celtic => "
+    let re = r_add(r_abs(r_sub(r_mul(z.re, z.re), r_mul(z.im, z.im))), c.re);
+    let im = r_add(r_mul(r_add(z.re, z.re), z.im), c.im);
+    return Complex(re, im);"
+            .to_string(),
+    };
+    format!("// One step of the orbit, {formula}.\nfn orbit_step(z: Complex, c: Complex) -> Complex {{{body}\n}}\n")
+}
+
+/// `escaped` for the escape test and its f32 limit, the squared radius
+/// for the circle. The limit must be finite.
+fn escaped(test: EscapeTest, limit: f32) -> String {
+    let test = match test {
+        EscapeTest::Circle => {
+            format!("r_f32(r_add(r_mul(z.re, z.re), r_mul(z.im, z.im))) > {limit:?}")
+        }
+        EscapeTest::Real => format!("abs(r_f32(z.re)) > {limit:?}"),
+        EscapeTest::Imag => format!("abs(r_f32(z.im)) > {limit:?}"),
+        EscapeTest::Manhattan => format!("abs(r_f32(z.re)) + abs(r_f32(z.im)) > {limit:?}"),
+        EscapeTest::Square => format!("max(abs(r_f32(z.re)), abs(r_f32(z.im))) > {limit:?}"),
+    };
+    format!("fn escaped(z: Complex) -> bool {{\n    return {test};\n}}\n")
+}
+
+/// Kernel source with the prelude defining `real` for `real_type` and the
+/// orbit step and escape test of `params`.
+pub fn source(real_type: ShaderReal, params: &RenderParams) -> String {
+    let prelude = match real_type {
+        ShaderReal::F32 => include_str!("real_f32.wgsl"),
+        ShaderReal::F64 => include_str!("real_f64.wgsl"),
+        ShaderReal::DoubleFloat => include_str!("real_df.wgsl"),
+    };
+    format!(
+        "{prelude}\n{}\n{}\n{}",
+        orbit_step(params.formula),
+        escaped(params.escape_test, params.escape_limit()),
+        include_str!("shader.wgsl")
+    )
+}
+
+/// Kernel checking that the double-float prelude keeps the low parts of
+/// its results on the device.
+pub fn df_check_source() -> String {
+    format!(
+        "{}\n{}",
+        include_str!("real_df.wgsl"),
+        include_str!("df_check.wgsl")
+    )
+}
@@ -1,98 +1,389 @@
+// The kernel is compiled after one of the real_*.wgsl preludes, which
+// define the `real` type the orbits are iterated in and its operations,
+// and the `orbit_step` and `escaped` of the render, see shader.rs.
+
 @group(0) @binding(0)
 var<storage, read_write> counts: array<atomic<u32>>; // this is used as both input and output for convenience
 
-@group(0) @binding(1)
-var<storage, read> prng_data: array<f32>; // this is used as both input and output for convenience
-
+// Coordinates are host f64 values split into hi and lo f32 parts:
+// (re hi, re lo, im hi, im lo).
 struct GPUVars {
+    lower_left: vec4f,
+    upper_right: vec4f,
+    zoom_lower_left: vec4f,
+    zoom_upper_right: vec4f,
+    // fixed c of Julia mode
+    julia_c: vec4f,
+    // rows of the matrix projecting (z.re, z.im, c.re, c.im) onto the screen
+    projection_x: vec4f,
+    projection_y: vec4f,
+    channel_iterations: vec4<u32>,
     width: u32,
     height: u32,
     max_iterations: u32,
-    ll_re: f32,
-    ll_im: f32,
-    ur_re: f32,
-    ur_im: f32,
-    zoom_ll_re: f32,
-    zoom_ll_im: f32,
-    zoom_ur_re: f32,
-    zoom_ur_im: f32,
+    mode: u32,
+    num_channels: u32,
+    min_iterations: u32,
+    sampling: u32,
+    interior_check: u32,
+    seed_lo: u32,
+    seed_hi: u32,
+    // screen rectangle binned by this dispatch, the orbits are the same
+    // for every tile of a batch
+    tile_x: u32,
+    tile_y: u32,
+    tile_width: u32,
+    tile_height: u32,
+    // stats are counted in the first tile and chains advanced in the last
+    first_tile: u32,
+    last_tile: u32,
+    // samples are the starting point z0 of an orbit with c = julia_c
+    // instead of c with z0 = 0
+    julia: u32,
+    _pad0: u32,
+    _pad1: u32,
+    _pad2: u32,
 }
 
+const MODE_BUDDHABROT: u32 = 0u;
+const MODE_ANTI: u32 = 1u;
+
+const SAMPLING_UNIFORM: u32 = 0u;
+const SAMPLING_METROPOLIS: u32 = 1u;
+
+// total count one Metropolis-Hastings sample spreads over its orbit
+const MH_WEIGHT: f32 = 1024.0;
+// chance of mutating the current sample instead of proposing a uniform one
+const MH_SMALL_STEP: f32 = 0.8;
+
 @group(0) @binding(2)
 var<uniform> vars_data: GPUVars;
 
-fn world_to_screen(cr: f32, ci: f32) -> vec2f {
+// Metropolis-Hastings chain of one thread, kept between dispatches.
+// contrib is the number of counts the current sample adds to the frame
+// and is 0 while the chain has not found a starting point yet. The sample
+// is stored as the hi and lo parts of each real.
+struct Chain {
+    p1: vec2f,
+    p2: vec2f,
+    contrib: u32,
+    mask: u32,
+}
+
+@group(0) @binding(3)
+var<storage, read_write> chains: array<Chain>;
+
+// number of samples caught by each interior test, indexed by region
+@group(0) @binding(4)
+var<storage, read_write> stats: array<atomic<u32>>;
+
+// high words of counts, incremented whenever adding to a count wraps
+@group(0) @binding(5)
+var<storage, read_write> counts_hi: array<atomic<u32>>;
+
+const REGION_CARDIOID: u32 = 0u;
+const REGION_BULB: u32 = 1u;
+const REGION_NONE: u32 = 2u;
+
+struct Complex {
+    re: real,
+    im: real,
+}
+
+// Starting point and parameter of an orbit.
+struct Orbit {
+    z0: Complex,
+    c: Complex,
+}
+
+fn window_corner(v: vec4f) -> Complex {
+    return Complex(r2(v.x, v.y), r2(v.z, v.w));
+}
+
+// Adds weight to a 64-bit count split over counts and counts_hi.
+fn add_count(idx: u32, weight: u32) {
+    let old = atomicAdd(&counts[idx], weight);
+    if old > 0xffffffffu - weight {
+        atomicAdd(&counts_hi[idx], 1u);
+    }
+}
+
+fn project(row: vec4f, z: Complex, c: Complex) -> real {
+    let zp = r_add(r_mul(r(row.x), z.re), r_mul(r(row.y), z.im));
+    let cp = r_add(r_mul(r(row.z), c.re), r_mul(r(row.w), c.im));
+    return r_add(zp, cp);
+}
+
+fn world_to_screen(z: Complex, c: Complex) -> vec2f {
+    let zoom_ll = window_corner(vars_data.zoom_lower_left);
+    let zoom_ur = window_corner(vars_data.zoom_upper_right);
     let w = f32(vars_data.width);
     let h = f32(vars_data.height);
-    let x = (cr - vars_data.zoom_ll_re) / (vars_data.zoom_ur_re - vars_data.zoom_ll_re) * w;
-    let y = (ci - vars_data.zoom_ll_im) / (vars_data.zoom_ur_im - vars_data.zoom_ll_im) * h;
+    let px = project(vars_data.projection_x, z, c);
+    let py = project(vars_data.projection_y, z, c);
+    let x = r_f32(r_sub(px, zoom_ll.re)) / r_f32(r_sub(zoom_ur.re, zoom_ll.re)) * w;
+    let y = r_f32(r_sub(py, zoom_ll.im)) / r_f32(r_sub(zoom_ur.im, zoom_ll.im)) * h;
     return vec2f(x, y);
 }
 
-fn buddhabrot_iterations(p1: f32, p2: f32) {
-    let re = p1 * (vars_data.ur_re - vars_data.ll_re) + vars_data.ll_re;
-    let im = p2 * (vars_data.ur_im - vars_data.ll_im) + vars_data.ll_im;
+fn channel_counts(escape_iters: u32, ch: u32) -> bool {
+    let limit = vars_data.channel_iterations[ch];
+    if vars_data.mode == MODE_ANTI {
+        return escape_iters >= limit;
+    }
+    return escape_iters < limit;
+}
+
+// Maps a sample in [0, 1)^2 onto the sample window, which holds c or, in
+// Julia mode, z0.
+fn sample_orbit(p1: real, p2: real) -> Orbit {
+    let ll = window_corner(vars_data.lower_left);
+    let ur = window_corner(vars_data.upper_right);
+    let re = r_add(r_mul(p1, r_sub(ur.re, ll.re)), ll.re);
+    let im = r_add(r_mul(p2, r_sub(ur.im, ll.im)), ll.im);
+    if vars_data.julia != 0u {
+        return Orbit(Complex(re, im), window_corner(vars_data.julia_c));
+    }
+    return Orbit(Complex(r(0.0), r(0.0)), Complex(re, im));
+}
 
-    // check for escape
+// Points in the main cardioid and the period-2 bulb never escape, so they
+// can be classified without running the escape loop.
+fn interior_region(c: Complex) -> u32 {
+    if vars_data.interior_check == 0u {
+        return REGION_NONE;
+    }
+    let x = r_sub(c.re, r(0.25));
+    let y2 = r_mul(c.im, c.im);
+    let q = r_add(r_mul(x, x), y2);
+    if r_le(r_mul(q, r_add(q, x)), r_mul(r(0.25), y2)) {
+        return REGION_CARDIOID;
+    }
+    let x1 = r_add(c.re, r(1.0));
+    if r_le(r_add(r_mul(x1, x1), y2), r(0.0625)) {
+        return REGION_BULB;
+    }
+    return REGION_NONE;
+}
+
+fn escape_iterations(o: Orbit) -> u32 {
+    // check for escape, max_iterations is the highest channel limit
     var iters: u32 = 0u;
-    var r: f32 = 0.0;
-    var i: f32 = 0.0;
-    var cr: f32 = re;
-    var ci: f32 = im;
+    var z = o.z0;
     loop {
         iters = iters + 1;
         if iters >= vars_data.max_iterations {
-            return;
+            break;
         }
 
-        let tr = r;
-        r = r*r - i*i + cr;
-        i = 2*tr*i + ci;
+        z = orbit_step(z, o.c);
 
-        if r * r + i * i > 8.0 {
+        if escaped(z) {
             break;
         }
     }
+    return iters;
+}
 
-    // now redo with counts
-    iters = 0u;
-    r = 0.0;
-    i = 0.0;
-    cr = re;
-    ci = im;
+// Runs the escape test and returns a bit per channel the orbit counts in.
+fn orbit_mask(o: Orbit) -> u32 {
+    var iters = vars_data.max_iterations;
+    let region = interior_region(o.c);
+    if region == REGION_NONE {
+        iters = escape_iterations(o);
+    } else {
+        if vars_data.first_tile != 0u {
+            atomicAdd(&stats[region], 1u);
+        }
+        // interior orbits are exactly what the anti-Buddhabrot counts
+        if vars_data.mode != MODE_ANTI {
+            return 0u;
+        }
+    }
+
+    // short orbits are dropped
+    if iters < vars_data.min_iterations {
+        return 0u;
+    }
+
+    // work out which channels this orbit contributes to
+    var channel_mask: u32 = 0u;
+    for (var ch: u32 = 0u; ch < vars_data.num_channels; ch = ch + 1u) {
+        if channel_counts(iters, ch) {
+            channel_mask = channel_mask | (1u << ch);
+        }
+    }
+    return channel_mask;
+}
+
+// Walks the orbit again adding weight to every pixel of the tile it lands
+// on and returns the number of hits in the whole frame. A weight of 0
+// only counts the hits.
+fn orbit_points(o: Orbit, channel_mask: u32, weight: u32) -> u32 {
+    // bounded orbits stop at the channel limit
+    let channel_size = vars_data.tile_width * vars_data.tile_height;
+    var hits: u32 = 0u;
+    var iters: u32 = 0u;
+    var z = o.z0;
     loop {
         iters = iters + 1;
+        if iters >= vars_data.max_iterations {
+            break;
+        }
 
-        let tr = r;
-        r = r*r - i*i + cr;
-        i = 2*tr*i + ci;
+        z = orbit_step(z, o.c);
 
-        if r * r + i * i > 8.0 {
+        if escaped(z) {
             break;
         }
 
-        let pos = world_to_screen(r, i);
+        let pos = world_to_screen(z, o.c);
         if pos.x >= 0.0 && u32(pos.x) < vars_data.width && pos.y >= 0 && u32(pos.y) < vars_data.height {
-            let idx = u32(pos.y) * vars_data.width + u32(pos.x);
-            atomicAdd(&counts[idx], 1u);
+            let tx = u32(pos.x) - vars_data.tile_x;
+            let ty = u32(pos.y) - vars_data.tile_y;
+            let in_tile = u32(pos.x) >= vars_data.tile_x && tx < vars_data.tile_width
+                && u32(pos.y) >= vars_data.tile_y && ty < vars_data.tile_height;
+            let idx = ty * vars_data.tile_width + tx;
+            for (var ch: u32 = 0u; ch < vars_data.num_channels; ch = ch + 1u) {
+                if (channel_mask & (1u << ch)) != 0u && iters < vars_data.channel_iterations[ch] {
+                    hits = hits + 1u;
+                    if weight > 0u && in_tile {
+                        add_count(ch * channel_size + idx, weight);
+                    }
+                }
+            }
         }
     }
+    return hits;
+}
+
+fn buddhabrot_iterations(p1: real, p2: real) {
+    let o = sample_orbit(p1, p2);
+    let channel_mask = orbit_mask(o);
+    if channel_mask == 0u {
+        return;
+    }
+    orbit_points(o, channel_mask, 1u);
+}
+
+// pcg4d hash from Jarzynski and Olano, "Hash Functions for GPU Rendering"
+fn pcg4d(v_in: vec4<u32>) -> vec4<u32> {
+    var v = v_in * 1664525u + 1013904223u;
+    v.x += v.y * v.w;
+    v.y += v.z * v.x;
+    v.z += v.x * v.y;
+    v.w += v.y * v.z;
+    v ^= v >> vec4<u32>(16u);
+    v.x += v.y * v.w;
+    v.y += v.z * v.x;
+    v.z += v.x * v.y;
+    v.w += v.y * v.z;
+    return v;
+}
+
+// Counter based random numbers in [0, 1): draw k of thread t for the
+// dispatch seed, so no state has to be kept between calls.
+fn random4(t: u32, k: u32) -> vec4f {
+    let v = pcg4d(vec4<u32>(t, k, vars_data.seed_lo, vars_data.seed_hi));
+    return vec4f(v >> vec4<u32>(8u)) / 16777216.0;
+}
+
+// Sample coordinate in [0, 1) from two draws of random4, so that double
+// precision gets 48 random bits instead of a 2^-24 grid. Single precision
+// only uses hi.
+fn r_uniform(hi: f32, lo: f32) -> real {
+    return r2(hi, lo / 16777216.0);
+}
+
+// One Metropolis-Hastings step: propose either a mutation of the chain's
+// sample or a fresh uniform sample, accept it with probability
+// new hits / old hits, then add the current sample with weight
+// MH_WEIGHT / hits so each step contributes the same total on average.
+fn metropolis_iterations(t: u32) {
+    var chain = chains[t];
+    var p1 = r2(chain.p1.x, chain.p1.y);
+    var p2 = r2(chain.p2.x, chain.p2.y);
+    let r0 = random4(t, 0u);
+    let r1 = random4(t, 1u);
+    let lo = random4(t, 2u);
+    let u1 = r_uniform(r0.x, lo.x);
+    let u2 = r_uniform(r0.y, lo.y);
+
+    if chain.contrib == 0u {
+        // no starting point yet, try the uniform sample
+        let o = sample_orbit(u1, u2);
+        let channel_mask = orbit_mask(o);
+        var hits = 0u;
+        if channel_mask != 0u {
+            hits = orbit_points(o, channel_mask, 0u);
+        }
+        if hits == 0u {
+            return;
+        }
+        p1 = u1;
+        p2 = u2;
+        chain.contrib = hits;
+        chain.mask = channel_mask;
+    } else {
+        var q1 = u1;
+        var q2 = u2;
+        if r0.z < MH_SMALL_STEP {
+            // symmetric step scaled to the zoom window over several octaves
+            let scale = exp2(-8.0 * r0.w);
+            let ll = window_corner(vars_data.lower_left);
+            let ur = window_corner(vars_data.upper_right);
+            let zoom_ll = window_corner(vars_data.zoom_lower_left);
+            let zoom_ur = window_corner(vars_data.zoom_upper_right);
+            let sigma_re = r_f32(r_sub(zoom_ur.re, zoom_ll.re)) / r_f32(r_sub(ur.re, ll.re));
+            let sigma_im = r_f32(r_sub(zoom_ur.im, zoom_ll.im)) / r_f32(r_sub(ur.im, ll.im));
+            q1 = r_add(p1, r((r1.x - 0.5) * 2.0 * scale * sigma_re));
+            q2 = r_add(p2, r((r1.y - 0.5) * 2.0 * scale * sigma_im));
+        }
+
+        var hits = 0u;
+        var channel_mask = 0u;
+        if r_le(r(0.0), q1) && r_lt(q1, r(1.0)) && r_le(r(0.0), q2) && r_lt(q2, r(1.0)) {
+            let o = sample_orbit(q1, q2);
+            channel_mask = orbit_mask(o);
+            if channel_mask != 0u {
+                hits = orbit_points(o, channel_mask, 0u);
+            }
+        }
+
+        if hits > 0u && r1.z * f32(chain.contrib) < f32(hits) {
+            p1 = q1;
+            p2 = q2;
+            chain.contrib = hits;
+            chain.mask = channel_mask;
+        }
+    }
+
+    // spread MH_WEIGHT over the orbit, rounding stochastically
+    let w = MH_WEIGHT / f32(chain.contrib);
+    let weight = u32(floor(w)) + select(0u, 1u, r1.w < fract(w));
+    if vars_data.last_tile != 0u {
+        chain.p1 = r_parts(p1);
+        chain.p2 = r_parts(p2);
+        chains[t] = chain;
+    }
+    if weight > 0u {
+        orbit_points(sample_orbit(p1, p2), chain.mask, weight);
+    }
 }
 
 @compute
 @workgroup_size(64, 1, 1)
 fn main(@builtin(global_invocation_id) global_id: vec3<u32>, @builtin(num_workgroups) num_workgroups: vec3<u32>) {
     //num_workgroups.x = 100,
-    //num_workgroups.y = 50
-    //prng_data length = 640000
+    //num_workgroups.y = trials / 6400
     //global_id.x goes from 0 to 6400-1
-    //global_id.y goes from 0 to 50-1
-    //idx1 goes from 0 to 49*6400+6399 = 319999
-    //idx2 = 100*50*64/2
-
-    let idx1 = global_id.y * num_workgroups.x*64 + global_id.x;
-    let idx2 = num_workgroups.x * num_workgroups.y*64 + idx1;
-    let re = prng_data[idx1];
-    let im = prng_data[idx2];
-    buddhabrot_iterations(re, im);
+    //t goes from 0 to trials-1
+
+    let t = global_id.y * num_workgroups.x*64 + global_id.x;
+    if vars_data.sampling == SAMPLING_METROPOLIS {
+        metropolis_iterations(t);
+    } else {
+        let rnd = random4(t, 0u);
+        buddhabrot_iterations(r_uniform(rnd.x, rnd.z), r_uniform(rnd.y, rnd.w));
+    }
 }
@@ -0,0 +1,232 @@
+use anyhow::{ensure, Error};
+
+/// Curve applied to the counts between the black and white points.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ToneCurve {
+    /// Counts scaled linearly, the brightest spots dominate
+    #[default]
+    Linear,
+    /// x^(1/gamma)
+    Gamma,
+    /// Square root, the same as gamma 2
+    Sqrt,
+    /// log(1 + stretch x) / log(1 + stretch)
+    Log,
+    /// asinh(stretch x) / asinh(stretch), linear near black and
+    /// logarithmic towards white
+    Asinh,
+    /// Histogram equalisation, each output level gets about the same
+    /// number of pixels
+    Equalize,
+}
+
+/// How the counts of one channel are turned into brightness.
+///
+/// The black and white points are percentiles of the non-zero counts, so
+/// a white point below 100 keeps a few hot pixels from setting the scale.
+/// Counts between them are scaled to [0, 1] and passed through the curve.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ToneMap {
+    pub curve: ToneCurve,
+    pub gamma: f64,
+    pub stretch: f64,
+    pub black_point: f64,
+    pub white_point: f64,
+}
+
+impl Default for ToneMap {
+    fn default() -> Self {
+        Self {
+            curve: ToneCurve::default(),
+            gamma: 2.2,
+            stretch: 1000.0,
+            black_point: 0.0,
+            white_point: 100.0,
+        }
+    }
+}
+
+/// Count at percentile `p` of the sorted counts.
+fn percentile(sorted: &[u64], p: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let n = ((p / 100.0) * (sorted.len() - 1) as f64).round() as usize;
+    sorted[n.min(sorted.len() - 1)]
+}
+
+impl ToneMap {
+    pub fn new(
+        curve: ToneCurve,
+        gamma: f64,
+        stretch: f64,
+        black_point: f64,
+        white_point: f64,
+    ) -> Result<Self, Error> {
+        ensure!(gamma > 0.0, "gamma must be positive, got {gamma}");
+        ensure!(stretch > 0.0, "stretch must be positive, got {stretch}");
+        ensure!(
+            (0.0..100.0).contains(&black_point)
+                && white_point <= 100.0
+                && black_point < white_point,
+            "black point ({black_point}) must be a percentile below the white point ({white_point})"
+        );
+        Ok(Self {
+            curve,
+            gamma,
+            stretch,
+            black_point,
+            white_point,
+        })
+    }
+
+    /// Maps every count of a channel to a brightness in [0, 1].
+    pub fn apply(&self, counts: &[u64]) -> Vec<f64> {
+        let mut sorted = counts
+            .iter()
+            .copied()
+            .filter(|c| *c > 0)
+            .collect::<Vec<_>>();
+        sorted.sort_unstable();
+
+        // a black point of 0 keeps every non-zero count above black
+        let black = match self.black_point {
+            p if p > 0.0 => percentile(&sorted, p),
+            _ => 0,
+        };
+        let white = percentile(&sorted, self.white_point).max(black + 1);
+        log::info!("black point {black}, white point {white}");
+
+        // equalisation ranks the counts between the two points
+        let rank = |c: u64| sorted.partition_point(|s| *s <= c);
+        let (black_rank, white_rank) = (rank(black), rank(white));
+
+        counts
+            .iter()
+            .map(|c| {
+                if *c <= black {
+                    return 0.0;
+                }
+                let c = (*c).min(white);
+                let x = (c - black) as f64 / (white - black) as f64;
+                match self.curve {
+                    ToneCurve::Linear => x,
+                    ToneCurve::Gamma => x.powf(1.0 / self.gamma),
+                    ToneCurve::Sqrt => x.sqrt(),
+                    ToneCurve::Log => (self.stretch * x).ln_1p() / self.stretch.ln_1p(),
+                    ToneCurve::Asinh => (self.stretch * x).asinh() / self.stretch.asinh(),
+                    ToneCurve::Equalize => {
+                        (rank(c) - black_rank) as f64 / (white_rank - black_rank).max(1) as f64
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CURVES: [ToneCurve; 6] = [
+        ToneCurve::Linear,
+        ToneCurve::Gamma,
+        ToneCurve::Sqrt,
+        ToneCurve::Log,
+        ToneCurve::Asinh,
+        ToneCurve::Equalize,
+    ];
+
+    fn tone(curve: ToneCurve, black_point: f64, white_point: f64) -> ToneMap {
+        ToneMap::new(curve, 2.2, 1000.0, black_point, white_point).unwrap()
+    }
+
+    #[test]
+    fn invalid_settings_are_rejected() {
+        for curve in CURVES {
+            assert!(ToneMap::new(curve, 0.0, 1000.0, 0.0, 100.0).is_err());
+            assert!(ToneMap::new(curve, 2.2, -1.0, 0.0, 100.0).is_err());
+            assert!(ToneMap::new(curve, 2.2, 1000.0, -1.0, 100.0).is_err());
+            assert!(ToneMap::new(curve, 2.2, 1000.0, 0.0, 100.5).is_err());
+            assert!(ToneMap::new(curve, 2.2, 1000.0, 50.0, 50.0).is_err());
+            assert!(ToneMap::new(curve, 2.2, 1000.0, 60.0, 40.0).is_err());
+            assert!(ToneMap::new(curve, 2.2, 1000.0, 100.0, 100.0).is_err());
+            assert!(ToneMap::new(curve, f64::NAN, 1000.0, 0.0, 100.0).is_err());
+        }
+    }
+
+    #[test]
+    fn curves_run_from_black_to_white() {
+        let counts = (0..=100).collect::<Vec<u64>>();
+        for curve in CURVES {
+            let levels = tone(curve, 0.0, 100.0).apply(&counts);
+            assert_eq!(levels[0], 0.0, "{curve:?}");
+            assert!((levels[100] - 1.0).abs() < 1e-12, "{curve:?}");
+            assert!(levels.windows(2).all(|w| w[0] < w[1]), "{curve:?}");
+        }
+    }
+
+    #[test]
+    fn curves_brighten_faint_counts() {
+        let counts = [0, 10, 100];
+        let linear = tone(ToneCurve::Linear, 0.0, 100.0).apply(&counts)[1];
+        assert!((linear - 0.1).abs() < 1e-12);
+        for curve in [
+            ToneCurve::Gamma,
+            ToneCurve::Sqrt,
+            ToneCurve::Log,
+            ToneCurve::Asinh,
+        ] {
+            assert!(
+                tone(curve, 0.0, 100.0).apply(&counts)[1] > linear,
+                "{curve:?}"
+            );
+        }
+        let sqrt = tone(ToneCurve::Sqrt, 0.0, 100.0).apply(&counts)[1];
+        assert!((sqrt - 0.1f64.sqrt()).abs() < 1e-12);
+    }
+
+    #[test]
+    fn all_zero_counts_stay_black() {
+        for curve in CURVES {
+            let levels = tone(curve, 10.0, 90.0).apply(&[0; 16]);
+            assert!(levels.iter().all(|l| *l == 0.0), "{curve:?}");
+        }
+        assert!(tone(ToneCurve::Linear, 0.0, 100.0).apply(&[]).is_empty());
+    }
+
+    #[test]
+    fn single_value_counts_are_white() {
+        for curve in CURVES {
+            let levels = tone(curve, 0.0, 100.0).apply(&[0, 5, 5, 5]);
+            assert_eq!(levels[0], 0.0, "{curve:?}");
+            assert!(
+                levels[1..].iter().all(|l| (*l - 1.0).abs() < 1e-12),
+                "{curve:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn percentiles_pick_black_and_white() {
+        // one hot pixel among counts of 1 to 99
+        let mut counts = (1..100).collect::<Vec<u64>>();
+        counts.push(1_000_000);
+        counts.push(0);
+        let levels = tone(ToneCurve::Linear, 10.0, 98.0).apply(&counts);
+
+        // the 10th percentile of the non-zero counts is 11 and the 98th is 98
+        assert_eq!(levels[10], 0.0);
+        assert!((levels[11] - 1.0 / 87.0).abs() < 1e-12);
+        assert_eq!(levels[97], 1.0);
+        assert_eq!(levels[99], 1.0);
+        assert_eq!(levels[100], 0.0);
+    }
+
+    #[test]
+    fn equalize_spreads_levels_by_rank() {
+        let counts = [0, 1, 1, 1, 2, 1000];
+        let levels = tone(ToneCurve::Equalize, 0.0, 100.0).apply(&counts);
+        assert_eq!(levels, vec![0.0, 0.6, 0.6, 0.6, 0.8, 1.0]);
+    }
+}